@@ -0,0 +1,413 @@
+//! Benchmarks for each layer: parsing, the engine on its own, and the task pool around it
+//!
+//! Inputs come from the generator with a fixed seed, so runs are comparable.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use payment_engine::csv::CsvParser;
+use payment_engine::generate::{Generator, GeneratorConfig};
+use payment_engine::input::Input;
+use payment_engine::pipeline::ParallelParser;
+use payment_engine::rates::RateTable;
+use payment_engine::sharding::Sharding;
+use payment_engine::task_pool::TaskPool;
+use payment_engine::transaction::{Currency, Kind, Transaction, TransactionId, UserId};
+use payment_engine::transaction_engine::TransactionEngine;
+use rust_decimal::Decimal;
+use std::io::Cursor;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+const TRANSACTIONS: usize = 10_000;
+const CLIENTS: UserId = 50;
+
+fn generated(transactions: usize, mix: &str) -> Vec<Transaction> {
+    Generator::new(GeneratorConfig {
+        transactions,
+        clients: CLIENTS,
+        mix: mix.parse().unwrap(),
+        ..GeneratorConfig::default()
+    })
+    .unwrap()
+    .collect::<Result<_, _>>()
+    .unwrap()
+}
+
+fn transaction(kind: Kind, i: usize, amount: Option<i64>) -> Transaction {
+    let client = (i % usize::from(CLIENTS)) as UserId;
+
+    Transaction {
+        kind,
+        client,
+        transaction_id: i as TransactionId,
+        amount: amount.map(Decimal::from),
+        currency: Currency::default(),
+        to_currency: (kind == Kind::Exchange).then(|| Currency::from("EUR")),
+        rate: None,
+        to_client: (kind == Kind::Transfer).then_some((client + 1) % CLIENTS),
+        timestamp: None,
+    }
+}
+
+fn parsing(c: &mut Criterion) {
+    let mut csv = Vec::new();
+    Generator::new(GeneratorConfig {
+        transactions: TRANSACTIONS,
+        ..GeneratorConfig::default()
+    })
+    .unwrap()
+    .write_csv(&mut csv)
+    .unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+
+    let mut group = c.benchmark_group("csv_parser");
+    group.throughput(Throughput::Elements(TRANSACTIONS as u64));
+    group.bench_function("line_to_transaction", |b| {
+        b.iter(|| {
+            let mut lines = csv.lines();
+            let header = CsvParser::valid_line(lines.next().unwrap().to_string()).unwrap();
+            let mut parser = CsvParser::new(header).unwrap();
+
+            for line in lines {
+                let line = CsvParser::valid_line(line.to_string()).unwrap();
+                parser.line_to_transaction(line).unwrap();
+            }
+        })
+    });
+
+    let path =
+        std::env::temp_dir().join(format!("payment_engine-bench-{}.csv", std::process::id()));
+    std::fs::write(&path, &csv).unwrap();
+    for (name, mapped) in [("mapped", true), ("streamed", false)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let input = if mapped {
+                    Input::open(&path).unwrap()
+                } else {
+                    Input::Stream(Box::new(Cursor::new(csv.as_bytes().to_vec())))
+                };
+                let (header, mut lines) = input.lines().unwrap();
+                let mut parser = CsvParser::new(header).unwrap();
+
+                while let Some((_, line)) = lines.next_line().unwrap() {
+                    let line = CsvParser::valid_bytes(line).unwrap();
+                    parser.bytes_to_transaction(line).unwrap();
+                }
+            })
+        });
+    }
+    std::fs::remove_file(path).unwrap();
+    group.finish();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    // How this scales across cores is unmeasured, as on a single core the parsers take turns
+    let mut group = c.benchmark_group("pipeline");
+    group.throughput(Throughput::Elements(TRANSACTIONS as u64));
+
+    for parsers in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(parsers),
+            &parsers,
+            |b, &parsers| {
+                b.iter(|| {
+                    // Small chunks, so there are enough to go around
+                    let input = Input::Stream(Box::new(Cursor::new(csv.clone())));
+                    let mut parser = ParallelParser::new(input, parsers, 16 * 1024, None).unwrap();
+                    runtime.block_on(async { while parser.next().await.unwrap().is_some() {} })
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn engine(c: &mut Criterion) {
+    let engine = || {
+        TransactionEngine::default()
+            .with_rates(RateTable::from_reader("from,to,rate\nUSD,EUR,0.9\n".as_bytes()).unwrap())
+    };
+
+    // Each kind is applied to an engine that has already had `before` applied
+    let kinds: [(Kind, &[Kind], Option<i64>); 7] = [
+        (Kind::Deposit, &[], Some(100)),
+        (Kind::Withdrawal, &[Kind::Deposit], Some(1)),
+        (Kind::Exchange, &[Kind::Deposit], Some(1)),
+        (Kind::Transfer, &[Kind::Deposit], Some(1)),
+        (Kind::Dispute, &[Kind::Deposit], None),
+        (Kind::Resolve, &[Kind::Deposit, Kind::Dispute], None),
+        (Kind::Chargeback, &[Kind::Deposit, Kind::Dispute], None),
+    ];
+
+    let mut group = c.benchmark_group("add_transaction");
+    group.throughput(Throughput::Elements(TRANSACTIONS as u64));
+
+    for (kind, before, amount) in kinds {
+        group.bench_function(kind.to_string(), |b| {
+            b.iter_batched(
+                || {
+                    let mut engine = engine();
+                    for before in before {
+                        let amount = (*before == Kind::Deposit).then_some(100);
+                        for i in 0..TRANSACTIONS {
+                            engine
+                                .add_transaction(transaction(*before, i, amount))
+                                .unwrap();
+                        }
+                    }
+                    let transactions: Vec<Transaction> = (0..TRANSACTIONS)
+                        .map(|i| transaction(kind, i, amount))
+                        .collect();
+                    (engine, transactions)
+                },
+                |(mut engine, transactions)| {
+                    for transaction in transactions {
+                        engine.add_transaction(transaction).unwrap();
+                    }
+                    engine
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    // Deposits again, logged at info like the CLI does by default, for what logging costs
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new("info"))
+        .with_writer(std::io::sink)
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        group.bench_function("deposit_logged", |b| {
+            b.iter_batched(
+                || {
+                    (0..TRANSACTIONS)
+                        .map(|i| transaction(Kind::Deposit, i, Some(100)))
+                        .collect::<Vec<_>>()
+                },
+                |transactions| {
+                    let mut engine = engine();
+                    for transaction in transactions {
+                        engine.add_transaction(transaction).unwrap();
+                    }
+                    engine
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    });
+
+    group.finish();
+}
+
+fn task_pool(c: &mut Criterion) {
+    let transactions = generated(
+        TRANSACTIONS,
+        "deposit=60,withdrawal=25,dispute=5,resolve=3,chargeback=2,transfer=5",
+    );
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("task_pool");
+    group.throughput(Throughput::Elements(TRANSACTIONS as u64));
+    group.sample_size(20);
+
+    for workers in [1, 2, 4, 8] {
+        for queue_depth in [10, 1_000] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}_workers", workers), queue_depth),
+                &(workers, queue_depth),
+                |b, &(workers, queue_depth)| {
+                    b.iter(|| {
+                        runtime.block_on(async {
+                            let mut task_pool =
+                                TaskPool::new(TransactionEngine::default(), queue_depth, workers);
+                            for transaction in &transactions {
+                                task_pool
+                                    .add_transaction(transaction.clone())
+                                    .await
+                                    .unwrap();
+                            }
+                            task_pool.wait().await.unwrap()
+                        })
+                    })
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+fn task_pool_batches(c: &mut Criterion) {
+    let transactions = generated(
+        TRANSACTIONS,
+        "deposit=60,withdrawal=25,dispute=5,resolve=3,chargeback=2,transfer=5",
+    );
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("task_pool_batches");
+    group.throughput(Throughput::Elements(TRANSACTIONS as u64));
+    group.sample_size(20);
+
+    for workers in [1, 4] {
+        for batch_size in [1, 16, 256] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}_workers", workers), batch_size),
+                &(workers, batch_size),
+                |b, &(workers, batch_size)| {
+                    b.iter(|| {
+                        runtime.block_on(async {
+                            let mut task_pool =
+                                TaskPool::new(TransactionEngine::default(), 100, workers)
+                                    .with_batching(batch_size, Duration::from_millis(10));
+                            for transaction in &transactions {
+                                task_pool
+                                    .add_transaction(transaction.clone())
+                                    .await
+                                    .unwrap();
+                            }
+                            task_pool.wait().await.unwrap()
+                        })
+                    })
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+/// Merges 20 of the generator's 50 clients into client 0, and gives 15 more an ID that's a
+/// multiple of 4
+fn skewed_client(client: UserId) -> UserId {
+    match client {
+        0..=19 => 0,
+        20..=34 => client * 4,
+        _ => client,
+    }
+}
+
+/// Most transactions for one hot client, and most of the rest for clients that modulo puts on
+/// the same worker as it
+fn skewed_sharding(c: &mut Criterion) {
+    let transactions: Vec<Transaction> = generated(
+        TRANSACTIONS,
+        "deposit=60,withdrawal=25,dispute=5,resolve=3,chargeback=2,transfer=5",
+    )
+    .into_iter()
+    .map(|mut transaction| {
+        transaction.client = skewed_client(transaction.client);
+        transaction.to_client = transaction.to_client.map(skewed_client);
+        transaction
+    })
+    .collect();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("skewed_sharding");
+    group.throughput(Throughput::Elements(TRANSACTIONS as u64));
+    group.sample_size(20);
+
+    for sharding in [Sharding::Modulo, Sharding::Hash, Sharding::Rebalance] {
+        group.bench_function(format!("{:?}", sharding).to_lowercase(), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let mut task_pool = TaskPool::new(TransactionEngine::default(), 100, 4)
+                        .with_batching(256, Duration::from_millis(10))
+                        .with_sharding(sharding)
+                        // A real input has time for many rebalances, this one only has 10,000 jobs
+                        .with_rebalance_every(1_000);
+                    for transaction in &transactions {
+                        task_pool
+                            .add_transaction(transaction.clone())
+                            .await
+                            .unwrap();
+                    }
+                    task_pool.wait().await.unwrap()
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Shards applied on their own threads and merged, without the task pool's channels in the way.
+/// How this scales across cores is unmeasured, as on a single core the threads take turns
+fn shards(c: &mut Criterion) {
+    // Transfers need the task pool to cross between shards
+    let transactions = generated(
+        TRANSACTIONS,
+        "deposit=60,withdrawal=30,dispute=5,resolve=3,chargeback=2",
+    );
+
+    let mut group = c.benchmark_group("shards");
+    group.throughput(Throughput::Elements(TRANSACTIONS as u64));
+
+    for shards in [1, 2, 4, 8] {
+        let mut partitions = vec![Vec::new(); shards];
+        for transaction in &transactions {
+            partitions[usize::from(transaction.client) % shards].push(transaction.clone());
+        }
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(shards),
+            &partitions,
+            |b, partitions| {
+                b.iter_batched(
+                    || partitions.clone(),
+                    |partitions| {
+                        let mut engine = TransactionEngine::default();
+                        let shards: Vec<TransactionEngine> = std::thread::scope(|scope| {
+                            let handles: Vec<_> = partitions
+                                .into_iter()
+                                .map(|partition| {
+                                    let mut shard = engine.shard();
+                                    scope.spawn(move || {
+                                        for transaction in partition {
+                                            shard.add_transaction(transaction).unwrap();
+                                        }
+                                        shard
+                                    })
+                                })
+                                .collect();
+
+                            handles
+                                .into_iter()
+                                .map(|handle| handle.join().unwrap())
+                                .collect()
+                        });
+
+                        for shard in shards {
+                            engine.merge(shard).unwrap();
+                        }
+                        engine
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    parsing,
+    engine,
+    task_pool,
+    task_pool_batches,
+    skewed_sharding,
+    shards
+);
+criterion_main!(benches);
@@ -0,0 +1,41 @@
+//! Arbitrary input, read line by line the way the CLI reads it
+//!
+//! Malformed lines can fail to parse, but never panic, and never stop later lines from parsing.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use payment_engine::csv::CsvParser;
+use payment_engine::input::lines;
+
+/// The headers every well formed input has, which a well formed line must parse with
+const HEADERS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+fuzz_target!(|input: &[u8]| {
+    let mut lines = lines(input);
+
+    // The header is read as a string, as the CLI does. Anything that isn't ASCII is invalid
+    let header = match lines.next() {
+        Some(header) => String::from_utf8_lossy(header).into_owned(),
+        None => return,
+    };
+    let known_headers = header.split(',').map(str::trim).eq(HEADERS);
+
+    let mut parser = match CsvParser::valid_line(header).map(CsvParser::new) {
+        Some(Ok(parser)) => parser,
+        _ => return,
+    };
+
+    for line in lines {
+        if let Some(line) = CsvParser::valid_bytes(line) {
+            let _ = parser.bytes_to_transaction(line);
+        }
+    }
+
+    // Whatever came before, a well formed line still parses
+    let line = CsvParser::valid_bytes(b"deposit,1,1,1.0").expect("A valid line");
+    let transaction = parser.bytes_to_transaction(line);
+    if known_headers {
+        assert!(transaction.is_ok(), "{:?}", transaction);
+    }
+});
@@ -0,0 +1,75 @@
+//! Arbitrary sequences of transactions, applied to an engine
+//!
+//! Transactions can be rejected, or fail, but never panic, and must leave the books balanced
+//! with what flowed in and out of clients' accounts.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use payment_engine::rates::RateTable;
+use payment_engine::transaction::{Currency, Kind, Transaction, TransactionId, UserId};
+use payment_engine::transaction_engine::TransactionEngine;
+use payment_engine::verify::{self, Violation};
+use rust_decimal::Decimal;
+
+#[derive(Arbitrary, Debug)]
+struct FuzzTransaction {
+    kind: u8,
+    client: u8,
+    transaction_id: u8,
+    cents: Option<i64>,
+    euros: bool,
+    to_client: Option<u8>,
+}
+
+impl From<FuzzTransaction> for Transaction {
+    fn from(transaction: FuzzTransaction) -> Self {
+        let kind = match transaction.kind % 7 {
+            0 => Kind::Deposit,
+            1 => Kind::Withdrawal,
+            2 => Kind::Dispute,
+            3 => Kind::Resolve,
+            4 => Kind::Chargeback,
+            5 => Kind::Exchange,
+            _ => Kind::Transfer,
+        };
+
+        let (currency, to_currency) = if transaction.euros {
+            (Currency::from("EUR"), Currency::from("USD"))
+        } else {
+            (Currency::from("USD"), Currency::from("EUR"))
+        };
+
+        Transaction {
+            kind,
+            // Few enough clients and IDs that disputes and transfers find each other
+            client: UserId::from(transaction.client % 8),
+            transaction_id: TransactionId::from(transaction.transaction_id % 32),
+            amount: transaction.cents.map(|cents| Decimal::new(cents, 2)),
+            currency,
+            to_currency: Some(to_currency),
+            rate: None,
+            to_client: transaction
+                .to_client
+                .map(|to_client| UserId::from(to_client % 8)),
+            timestamp: None,
+        }
+    }
+}
+
+fuzz_target!(|transactions: Vec<FuzzTransaction>| {
+    let mut engine = TransactionEngine::default()
+        .with_rates(RateTable::from_reader("from,to,rate\nUSD,EUR,0.9\n".as_bytes()).unwrap());
+
+    for transaction in transactions {
+        let _ = engine.add_transaction(transaction.into());
+    }
+
+    for summary in engine.current_account_states() {
+        assert!(summary.held >= Decimal::ZERO, "{:?}", summary);
+    }
+
+    let violations: Vec<Violation> = verify::check(&engine);
+    assert!(violations.is_empty(), "{:?}", violations);
+});
@@ -0,0 +1,131 @@
+//! Double-entry bookkeeping
+//!
+//! Every change to a balance is a [`Posting`], which debits one account and credits another
+//! by the same amount in the same currency. Money only ever moves between accounts, so across
+//! every account the debits and credits balance, which a [`TrialBalance`] checks.
+
+use crate::transaction::{Currency, UserId};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Account {
+    /// What a client can withdraw, exchange or transfer
+    Available(UserId),
+
+    /// What is held from a client while their deposits are disputed
+    Held(UserId),
+
+    /// The house's cash. Deposits come in through here, and withdrawals and chargebacks go out
+    HouseCash,
+
+    /// Fees charged to clients
+    FeeIncome,
+
+    /// What clients owed once their deposits were charged back, which is written off
+    ChargebackLoss,
+
+    /// Funds converted between currencies. Exchanges credit it in one currency and debit it
+    /// in the other
+    Exchange,
+
+    /// Transfers debited from one client and not yet credited to the other
+    InTransit,
+}
+
+impl Account {
+    /// Whether the account's balance is normally a debit. Those are the house's assets and
+    /// losses; everything else is owed to someone, so is normally a credit.
+    pub fn is_debit_normal(&self) -> bool {
+        matches!(self, Self::HouseCash | Self::ChargebackLoss)
+    }
+
+    /// The client the account belongs to, if it isn't one of the house's
+    pub fn client(&self) -> Option<UserId> {
+        match self {
+            Self::Available(client) | Self::Held(client) => Some(*client),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Available(client) => write!(f, "client {} available", client),
+            Self::Held(client) => write!(f, "client {} held", client),
+            Self::HouseCash => f.write_str("house cash"),
+            Self::FeeIncome => f.write_str("fee income"),
+            Self::ChargebackLoss => f.write_str("chargeback loss"),
+            Self::Exchange => f.write_str("exchange"),
+            Self::InTransit => f.write_str("in transit"),
+        }
+    }
+}
+
+/// Moves an amount from one account to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Posting {
+    pub currency: Currency,
+    pub debit: Account,
+    pub credit: Account,
+    pub amount: Decimal,
+}
+
+impl Posting {
+    pub fn new(currency: &Currency, debit: Account, credit: Account, amount: Decimal) -> Self {
+        Self {
+            currency: currency.clone(),
+            debit,
+            credit,
+            amount,
+        }
+    }
+}
+
+/// The debit and credit balances of every account, in a single currency
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrialBalance {
+    pub currency: Currency,
+    pub debits: Decimal,
+    pub credits: Decimal,
+}
+
+impl TrialBalance {
+    pub fn new(currency: Currency) -> Self {
+        Self {
+            currency,
+            debits: Decimal::ZERO,
+            credits: Decimal::ZERO,
+        }
+    }
+
+    /// Adds an account's balance, where debits are positive and credits negative
+    pub fn add(&mut self, balance: Decimal) {
+        if balance.is_sign_negative() {
+            self.credits -= balance;
+        } else {
+            self.debits += balance;
+        }
+    }
+
+    /// Whether money was neither created nor destroyed
+    pub fn is_balanced(&self) -> bool {
+        self.debits == self.credits
+    }
+}
+
+#[test]
+fn trial_balance_sides() {
+    let mut trial_balance = TrialBalance::new(Currency::default());
+    trial_balance.add(Decimal::from(10));
+    trial_balance.add(Decimal::from(-4));
+    assert!(!trial_balance.is_balanced());
+
+    trial_balance.add(Decimal::from(-6));
+    assert!(trial_balance.is_balanced());
+    assert_eq!(trial_balance.credits, Decimal::from(10));
+    assert!(Account::HouseCash.is_debit_normal());
+    assert_eq!(Account::Held(3).client(), Some(3));
+}
@@ -1,29 +1,259 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use payment_engine::event_log::AsOf;
+use payment_engine::generate::{AmountDistribution, KindMix};
+use payment_engine::ledger::LedgerFormat;
+use payment_engine::sharding::{Sharding, REBALANCE_EVERY};
+use payment_engine::task_pool::OnFailure;
+use payment_engine::transaction::UserId;
+use payment_engine::transaction_engine::TimestampPolicy;
+use rust_decimal::Decimal;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 #[derive(Parser)]
+#[clap(subcommand_negates_reqs = true)]
 pub struct Args {
-    /// An input CSV file
-    #[clap(long_help = INPUT_LONG_ABOUT)]
-    pub input_csv: PathBuf,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// An input CSV file, which can be gzip compressed, or - for stdin
+    #[clap(long_help = INPUT_LONG_ABOUT, required = true)]
+    pub input_csv: Option<PathBuf>,
 
     /// Defaults to stdout if not set
     pub output_csv: Option<PathBuf>,
 
-    /// The maximum number items in queue for computation per worker thread
+    /// How to process the input
+    #[clap(long, value_enum, default_value_t = Mode::Auto)]
+    pub mode: Mode,
+
+    /// The maximum number of batches in queue for computation per worker thread
     #[clap(default_value_t = 1_000, short = 'd')]
     pub queue_depth: usize,
 
+    /// The number of threads parsing the input CSV
+    ///
+    /// The input is split into chunks of whole lines, which are parsed in parallel and put
+    /// back in order before they reach the workers.
+    #[clap(long, default_value_t = 1)]
+    pub parsers: usize,
+
+    /// The maximum number of transactions sent to a worker thread at once
+    ///
+    /// Larger batches spend less time passing messages between threads. Transfers between
+    /// workers send the source's batch early.
+    #[clap(long, default_value_t = 256)]
+    pub batch_size: usize,
+
+    /// The longest a transaction waits for its batch to fill before it's sent, in milliseconds
+    ///
+    /// Must be at least 1, as the input is polled this often.
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub flush_interval_ms: u64,
+
+    /// What to do when a worker fails to apply a transaction, like a deposit without an amount
+    ///
+    /// With continue, the output leaves out every client on a failed worker, and the exit code
+    /// is 2. In sync mode, any failure stops processing.
+    #[clap(long, value_enum, default_value_t = OnFailure::Stop)]
+    pub on_failure: OnFailure,
+
     /// The number of transaction engine worker threads
     ///
     /// 0 defaults to the number of threads available on the system.
     /// A negative number subtracts from the number of available threads (down to a minimum of 1).
     #[clap(default_value_t = 0, short = 'w')]
     pub workers: isize,
+
+    /// How clients are spread across worker threads
+    ///
+    /// Every transaction for a client goes to one worker at a time, so a single busy client
+    /// can't be spread out. Rebalancing moves other clients off its worker instead.
+    #[clap(long, value_enum, default_value_t = Sharding::Modulo)]
+    pub sharding: Sharding,
+
+    /// How many transactions to apply between looking for clients to move, with
+    /// `--sharding rebalance`
+    #[clap(long, default_value_t = REBALANCE_EVERY)]
+    pub rebalance_every: u64,
+
+    /// How often to log how busy each worker thread is, in seconds
+    ///
+    /// Shows each worker's queue occupancy, how long sending to it blocked, and how many
+    /// transactions it processed, to help pick the queue depth and number of workers. These
+    /// are always logged once processing has finished. Not used in sync mode.
+    #[clap(long)]
+    pub stats_interval_secs: Option<u64>,
+
+    /// A CSV of exchange rates, with from, to and rate columns
+    ///
+    /// Each row means one unit of `from` buys `rate` units of `to`. Exchanges that
+    /// have a rate column use that instead.
+    #[clap(long)]
+    pub rates: Option<PathBuf>,
+
+    /// A CSV of fees for deposits and withdrawals, with type, flat, percent, min and max columns
+    ///
+    /// Fees are booked to the house account. The fee for a deposit is refunded if the
+    /// deposit is charged back.
+    #[clap(long)]
+    pub fees: Option<PathBuf>,
+
+    /// A CSV of withdrawal limits, with client, max_withdrawal, max_withdrawals, window,
+    /// window_secs and max_daily_outflow columns
+    ///
+    /// A row without a client sets the limits for every client, and rows with a client
+    /// override them. Withdrawals over a limit are rejected, as are transfers and exchanges
+    /// over max_daily_outflow. Limits in time use the timestamp column, with days in UTC, so
+    /// max_daily_outflow rejects debits for clients without any timestamps.
+    #[clap(long)]
+    pub limits: Option<PathBuf>,
+
+    /// What to do with a transaction timestamped before an earlier one for the same client
+    #[clap(long, value_enum, default_value_t = TimestampPolicy::Warn)]
+    pub timestamp_policy: TimestampPolicy,
+
+    /// How many days after a deposit it can still be disputed
+    ///
+    /// Only enforced when both the deposit and dispute have timestamps.
+    #[clap(long)]
+    pub dispute_window_days: Option<u32>,
+
+    /// Stop processing at this point, and output balances as they were then
+    ///
+    /// One of tx:<id> to stop after the first transaction with that ID, line:<number> to
+    /// stop after that line of the input (the header is line 1), or time:<timestamp> to
+    /// leave out each client's transactions from their first one timestamped after it.
+    #[clap(long)]
+    pub as_of: Option<AsOf>,
+
+    /// Where to write a ledger of every applied transaction
+    ///
+    /// Each entry has the transaction's amount and fee, and the client's balance before
+    /// and after it. Exchanges have an entry for each currency.
+    #[clap(long)]
+    pub ledger: Option<PathBuf>,
+
+    /// How to write the ledger
+    #[clap(long, value_enum, default_value_t = LedgerFormat::Csv)]
+    pub ledger_format: LedgerFormat,
+
+    /// Serve Prometheus metrics over HTTP on this address while processing, like
+    /// 127.0.0.1:9898
+    ///
+    /// Counts transactions by kind, rejections by reason and parse errors, and tracks locked
+    /// accounts, funds held by disputes and each worker's queue depth.
+    #[clap(long)]
+    pub metrics_address: Option<SocketAddr>,
+
+    /// Write Prometheus metrics to this file once processing has finished
+    #[clap(long)]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Check that every client's balances add up, and that nothing was created or destroyed,
+    /// failing with every difference found if not
+    #[clap(long)]
+    pub verify: bool,
+
+    /// The lowest level of log to write, like warn, or a filter like payment_engine=debug
+    ///
+    /// Defaults to the RUST_LOG environment variable, or info if that's not set either.
+    #[clap(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// How to write logs
+    ///
+    /// With payment_engine::spans=debug in the log level, each transaction's logs carry its
+    /// client and transaction ID, in the span fields of JSON logs.
+    #[clap(long, value_enum, global = true, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Append logs to this file, rather than writing them to stderr
+    #[clap(long, global = true)]
+    pub log_file: Option<PathBuf>,
+}
+
+/// How logs are written
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A line of text for each, for reading
+    Text,
+
+    /// A JSON object on each line, for a log pipeline
+    Json,
+}
+
+/// Inputs smaller than this are processed on a single thread when the mode is auto
+const SYNC_INPUT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// How the input is read, parsed and applied
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Sync with a single worker, or an input file under 4 MiB, and async otherwise
+    Auto,
+
+    /// Everything on the main thread, without tokio or the task pool
+    Sync,
+
+    /// Parser threads feeding the task pool's workers
+    Async,
+}
+
+impl Mode {
+    /// Inputs of an unknown size, like stdin, are assumed to be large
+    pub fn is_sync(self, workers: isize, input_bytes: Option<u64>) -> bool {
+        match self {
+            Self::Auto => {
+                workers == 1 || matches!(input_bytes, Some(bytes) if bytes < SYNC_INPUT_BYTES)
+            }
+            Self::Sync => true,
+            Self::Async => false,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Write a synthetic input CSV, for benchmarks and tests
+    Generate(GenerateArgs),
+}
+
+#[derive(clap::Args)]
+pub struct GenerateArgs {
+    /// Defaults to stdout if not set
+    pub output_csv: Option<PathBuf>,
+
+    /// The number of clients, numbered from 0
+    #[clap(long, default_value_t = 50)]
+    pub clients: UserId,
+
+    /// The number of transactions
+    #[clap(long, default_value_t = 250_000)]
+    pub transactions: usize,
+
+    /// How often each kind of transaction is generated, as kind=weight pairs
+    ///
+    /// The kinds are deposit, withdrawal, dispute, resolve, chargeback and transfer. Disputes
+    /// are of the client's earlier deposits, and resolves and chargebacks of their open
+    /// disputes. When there are none, a deposit is generated instead.
+    #[clap(long, default_value_t = KindMix::default())]
+    pub mix: KindMix,
+
+    /// How amounts are spread around the mean
+    #[clap(long, value_enum, default_value_t = AmountDistribution::Exponential)]
+    pub amounts: AmountDistribution,
+
+    /// The mean amount of deposits, withdrawals and transfers, at most 1000000000
+    #[clap(long, default_value_t = Decimal::ONE_HUNDRED)]
+    pub mean_amount: Decimal,
+
+    /// The same seed always generates the same CSV
+    #[clap(long, default_value_t = 0)]
+    pub seed: u64,
 }
 
 const INPUT_LONG_ABOUT: &str = r#"
-An input CSV file
+An input CSV file, which can be gzip compressed, or - for stdin
 
 It is expected to be in the following example form:
 
@@ -33,4 +263,22 @@ deposit,           2,    2,      2.0
 deposit,           1,    3,      2.0
 withdrawal,        1,    4,      1.5
 withdrawal,        2,    5,      3.0
+
+An optional currency column gives the currency of each amount. Rows
+without a currency are in USD. Disputes, resolves and chargebacks
+always apply to the currency of the original deposit.
+
+Exchanges convert an amount from currency to to_currency, at the rate
+column or at the rate given by --rates:
+
+type,         client,   tx,   amount,   currency,   to_currency,   rate
+exchange,          1,    6,      1.0,        EUR,           USD,   1.08
+
+An optional timestamp column, in RFC 3339 form or as seconds since the
+Unix epoch, says when each transaction happened.
+
+Transfers move an amount from client to to_client:
+
+type,         client,   tx,   amount,   to_client
+transfer,          1,    7,      0.5,           2
 "#;
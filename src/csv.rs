@@ -1,49 +1,80 @@
 use crate::prelude::*;
+#[cfg(test)]
+use crate::transaction::Currency;
 use crate::transaction::Transaction;
 use csv::{DeserializeRecordsIntoIter, ReaderBuilder, Trim};
 use std::collections::VecDeque;
 
+#[derive(Clone)]
 pub struct LikelyValidLine(String);
 
+/// A [`LikelyValidLine`] borrowed from the input, like a memory mapped file. It's still copied
+/// into the reader's buffer to be parsed
+#[derive(Clone, Copy)]
+pub struct LikelyValidBytes<'a>(&'a [u8]);
+
 pub struct CsvParser {
+    headers: String,
     reader: DeserializeRecordsIntoIter<VecDeque<u8>, Transaction>,
 }
 
 impl CsvParser {
     pub fn line_to_transaction(&mut self, line: LikelyValidLine) -> Result<Transaction> {
+        self.parse(line.0.as_bytes())
+    }
+
+    pub fn bytes_to_transaction(&mut self, line: LikelyValidBytes) -> Result<Transaction> {
+        self.parse(line.0)
+    }
+
+    fn parse(&mut self, line: &[u8]) -> Result<Transaction> {
         {
             let reader = self.reader.reader_mut().get_mut();
 
-            reader.append(&mut VecDeque::from(line.0.into_bytes()));
+            reader.extend(line);
             reader.push_back(b'\n');
         }
 
-        let transaction = self
-            .reader
-            .next()
-            .expect("Line just added, there should be lines left!")?;
-
-        Ok(transaction)
+        match self.reader.next() {
+            Some(transaction) if !self.reader.reader().is_done() => Ok(transaction?),
+            // The reader only runs out if the line didn't end, like when it opens a quote it
+            // never closes. Once it has run out it won't read any more, so start over
+            _ => {
+                self.reader = Self::reader(&self.headers);
+                Err(anyhow!("Line does not end in a complete record"))
+            }
+        }
     }
 
     pub fn new(line_with_headers: LikelyValidLine) -> Result<Self> {
-        let mut reader = VecDeque::from(line_with_headers.0.into_bytes());
+        let headers = line_with_headers.0;
+        let reader = Self::reader(&headers);
+
+        Ok(Self { headers, reader })
+    }
+
+    fn reader(headers: &str) -> DeserializeRecordsIntoIter<VecDeque<u8>, Transaction> {
+        let mut reader = VecDeque::from(headers.as_bytes().to_vec());
         reader.push_back(b'\n');
 
-        let reader = ReaderBuilder::new()
+        ReaderBuilder::new()
             .trim(Trim::All)
             .from_reader(reader)
-            .into_deserialize();
-
-        Ok(Self { reader })
+            .into_deserialize()
     }
 
+    /// Lines that are empty, or have anything but ASCII, aren't valid. Neither are those with a
+    /// carriage return, which would end the record part way through the line
     pub fn valid_line(line: String) -> Option<LikelyValidLine> {
-        if line.is_empty() || !line.is_ascii() {
-            None
-        } else {
-            Some(LikelyValidLine(line))
-        }
+        Self::is_valid(line.as_bytes()).then_some(LikelyValidLine(line))
+    }
+
+    pub fn valid_bytes(line: &[u8]) -> Option<LikelyValidBytes<'_>> {
+        Self::is_valid(line).then_some(LikelyValidBytes(line))
+    }
+
+    fn is_valid(line: &[u8]) -> bool {
+        !line.is_empty() && line.is_ascii() && !line.contains(&b'\r')
     }
 }
 
@@ -62,3 +93,66 @@ dispute,      1,        1,
         .line_to_transaction(CsvParser::valid_line(String::from(lines.next().unwrap())).unwrap())
         .unwrap();
 }
+
+#[test]
+fn parse_with_currency() {
+    let input = r#"
+type,         client,   tx,   amount,   currency
+deposit,      1,        1,    1.0,      eur
+deposit,      1,        2,    1.0,
+"#;
+    let mut lines = input.lines();
+    lines.next();
+    let mut parser =
+        CsvParser::new(CsvParser::valid_line(String::from(lines.next().unwrap())).unwrap())
+            .unwrap();
+    let transaction = parser
+        .line_to_transaction(CsvParser::valid_line(String::from(lines.next().unwrap())).unwrap())
+        .unwrap();
+    assert_eq!(transaction.currency.to_string(), "EUR");
+
+    let transaction = parser
+        .line_to_transaction(CsvParser::valid_line(String::from(lines.next().unwrap())).unwrap())
+        .unwrap();
+    assert_eq!(transaction.currency.to_string(), Currency::DEFAULT);
+}
+
+#[test]
+fn parse_with_timestamps() {
+    let input = r#"
+type,         client,   tx,   amount,   timestamp
+deposit,      1,        1,    1.0,      2022-07-09T12:00:00+02:00
+deposit,      1,        2,    1.0,      1657360800
+"#;
+    let mut lines = input.lines();
+    lines.next();
+    let mut parser =
+        CsvParser::new(CsvParser::valid_line(String::from(lines.next().unwrap())).unwrap())
+            .unwrap();
+    let first = parser
+        .line_to_transaction(CsvParser::valid_line(String::from(lines.next().unwrap())).unwrap())
+        .unwrap();
+    let second = parser
+        .line_to_transaction(CsvParser::valid_line(String::from(lines.next().unwrap())).unwrap())
+        .unwrap();
+
+    assert_eq!(first.timestamp.unwrap().to_string(), "2022-07-09T10:00:00Z");
+    assert_eq!(first.timestamp, second.timestamp);
+}
+
+#[test]
+fn parse_after_unterminated_quote() {
+    let mut parser =
+        CsvParser::new(CsvParser::valid_line(String::from("type,client,tx,amount")).unwrap())
+            .unwrap();
+
+    assert!(parser
+        .line_to_transaction(CsvParser::valid_line(String::from("deposit,1,\"1,1.0")).unwrap())
+        .is_err());
+    assert!(CsvParser::valid_line(String::from("deposit,1,1,1.0\rdeposit")).is_none());
+
+    let transaction = parser
+        .line_to_transaction(CsvParser::valid_line(String::from("deposit,1,2,1.0")).unwrap())
+        .unwrap();
+    assert_eq!(transaction.transaction_id, 2);
+}
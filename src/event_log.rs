@@ -0,0 +1,338 @@
+//! Balances at a point in the past
+//!
+//! An [`EventLog`] keeps every transaction it is given, so balances can be found as they
+//! were at any earlier point by replaying the log up to that point. One can be read straight
+//! from the input with [`EventLog::from_input`], then queried as many times as needed.
+
+use crate::csv::CsvParser;
+use crate::input::Input;
+use crate::prelude::*;
+use crate::transaction::{Timestamp, Transaction, TransactionId, UserId};
+use crate::transaction_engine::{TransactionEngine, UserSummary};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// A point in the input to stop at
+///
+/// Parsed from `tx:<id>`, `line:<number>` or `time:<timestamp>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    /// Just after the first transaction with this ID
+    Transaction(TransactionId),
+
+    /// Just after this line of the input, where the header is line 1
+    Line(usize),
+
+    /// Just before each client's first transaction timestamped after this. Other clients'
+    /// transactions carry on past it
+    Time(Timestamp),
+}
+
+/// Where a transaction is, relative to an [`AsOf`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    /// The transaction is included, and more may follow
+    Before,
+
+    /// The transaction is the last one included
+    At,
+
+    /// The transaction isn't included, and neither is anything after it
+    After,
+
+    /// The transaction isn't included, but later ones may be
+    Skipped,
+}
+
+impl AsOf {
+    /// Where the transaction on the given line of the input is, relative to this point, going
+    /// by that transaction alone. See [`Cutoff`] for the whole input
+    pub fn position(&self, line: usize, transaction: &Transaction) -> Position {
+        match *self {
+            Self::Transaction(transaction_id) if transaction.transaction_id == transaction_id => {
+                Position::At
+            }
+            Self::Transaction(_) => Position::Before,
+            Self::Line(last_line) if line < last_line => Position::Before,
+            Self::Line(last_line) if line == last_line => Position::At,
+            Self::Line(_) => Position::After,
+            Self::Time(time) => match transaction.timestamp {
+                Some(timestamp) if timestamp > time => Position::Skipped,
+                _ => Position::Before,
+            },
+        }
+    }
+}
+
+/// Where each transaction of the input is relative to an [`AsOf`], given them in order
+///
+/// Once a client has a transaction past a time, all of their later transactions are
+/// skipped too, as those without a timestamp are taken to happen with the latest one.
+#[derive(Debug)]
+pub struct Cutoff {
+    as_of: AsOf,
+
+    /// Clients with a transaction past the time
+    clients_past: HashSet<UserId>,
+}
+
+impl Cutoff {
+    pub fn new(as_of: AsOf) -> Self {
+        Self {
+            as_of,
+            clients_past: HashSet::new(),
+        }
+    }
+
+    pub fn position(&mut self, line: usize, transaction: &Transaction) -> Position {
+        if self.clients_past.contains(&transaction.client) {
+            return Position::Skipped;
+        }
+
+        let position = self.as_of.position(line, transaction);
+        if position == Position::Skipped {
+            self.clients_past.insert(transaction.client);
+        }
+
+        position
+    }
+}
+
+impl FromStr for AsOf {
+    type Err = anyhow::Error;
+
+    fn from_str(as_of: &str) -> Result<Self> {
+        let (kind, value) = as_of.split_once(':').ok_or_else(|| {
+            anyhow!(
+                "Expected tx:<id>, line:<number> or time:<timestamp>, got {:?}",
+                as_of
+            )
+        })?;
+
+        match kind {
+            "tx" => Ok(Self::Transaction(value.parse()?)),
+            "line" => Ok(Self::Line(value.parse()?)),
+            "time" => Ok(Self::Time(
+                Timestamp::try_from(String::from(value)).map_err(|e| anyhow!(e))?,
+            )),
+            _ => Err(anyhow!(
+                "Expected tx, line or time before the colon, got {:?}",
+                kind
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AsOf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transaction(transaction_id) => write!(f, "tx:{}", transaction_id),
+            Self::Line(line) => write!(f, "line:{}", line),
+            Self::Time(time) => write!(f, "time:{}", time),
+        }
+    }
+}
+
+/// A transaction and the line of the input it came from
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub line: usize,
+    pub transaction: Transaction,
+}
+
+/// Every transaction given to an engine, in order
+#[derive(Default, Debug)]
+pub struct EventLog {
+    events: Vec<Event>,
+}
+
+impl EventLog {
+    /// Reads every transaction from the input, skipping invalid lines like the CLI does
+    pub fn from_input(input: Input) -> Result<Self> {
+        let (header_line, mut lines) = input.lines()?;
+        let mut csv_parser = CsvParser::new(header_line)?;
+        let mut log = Self::default();
+
+        while let Some((line_number, line)) = lines.next_line()? {
+            let valid_line = match CsvParser::valid_bytes(line) {
+                Some(valid_line) => valid_line,
+                None => {
+                    warn!("Get an invalid line, skipping");
+                    continue;
+                }
+            };
+
+            let transaction = csv_parser
+                .bytes_to_transaction(valid_line)
+                .with_context(|| format!("On line {}", line_number))?;
+            log.push(line_number, transaction);
+        }
+
+        Ok(log)
+    }
+
+    pub fn push(&mut self, line: usize, transaction: Transaction) {
+        self.events.push(Event { line, transaction });
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Replays the log into an engine, up to a point
+    ///
+    /// The engine should be new, and configured the same as the engine the log was first
+    /// given to, so the replay makes the same decisions.
+    pub fn replay(&self, mut engine: TransactionEngine, as_of: AsOf) -> Result<TransactionEngine> {
+        let mut cutoff = Cutoff::new(as_of);
+
+        for event in &self.events {
+            match cutoff.position(event.line, &event.transaction) {
+                Position::Before => engine.add_transaction(event.transaction.clone())?,
+                Position::At => {
+                    engine.add_transaction(event.transaction.clone())?;
+                    break;
+                }
+                Position::After => break,
+                Position::Skipped => {}
+            }
+        }
+
+        Ok(engine)
+    }
+
+    /// A client's balances at a point, with one summary per currency
+    pub fn balances_as_of(
+        &self,
+        engine: TransactionEngine,
+        as_of: AsOf,
+        client: UserId,
+    ) -> Result<Vec<UserSummary>> {
+        Ok(self
+            .replay(engine, as_of)?
+            .current_account_states()
+            .into_iter()
+            .filter(|summary| summary.client == client)
+            .collect())
+    }
+}
+
+#[test]
+fn balances_at_each_point() -> Result<()> {
+    use crate::transaction::{Currency, Kind};
+    use rust_decimal::Decimal;
+
+    let mut log = EventLog::default();
+    for (transaction_id, (kind, amount, seconds)) in [
+        (Kind::Deposit, 10, 100),
+        (Kind::Withdrawal, 3, 200),
+        (Kind::Deposit, 5, 300),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        log.push(
+            transaction_id + 2,
+            Transaction {
+                kind,
+                client: 1,
+                transaction_id: transaction_id as TransactionId,
+                amount: Some(Decimal::from(amount)),
+                currency: Currency::default(),
+                to_currency: None,
+                rate: None,
+                to_client: None,
+                timestamp: Timestamp::from_epoch_seconds(seconds),
+            },
+        );
+    }
+
+    let total_as_of = |as_of: &str| -> Result<Decimal> {
+        Ok(log
+            .balances_as_of(TransactionEngine::default(), as_of.parse()?, 1)?
+            .first()
+            .map(|summary| summary.total)
+            .unwrap_or_default())
+    };
+
+    assert_eq!(total_as_of("tx:0")?, Decimal::from(10));
+    assert_eq!(total_as_of("line:3")?, Decimal::from(7));
+    assert_eq!(total_as_of("time:1970-01-01T00:04:59Z")?, Decimal::from(7));
+    assert_eq!(total_as_of("time:300")?, Decimal::from(12));
+    assert!("block:1".parse::<AsOf>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn time_cuts_off_each_client() -> Result<()> {
+    use crate::transaction::{Currency, Kind};
+    use rust_decimal::Decimal;
+
+    let mut log = EventLog::default();
+    for (transaction_id, (client, seconds)) in [
+        (1, Some(100)),
+        (1, Some(300)),
+        // Taken to happen with client 1's transaction at 300
+        (1, None),
+        // After a later timestamp in the input, but not past the time
+        (2, Some(200)),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        log.push(
+            transaction_id + 2,
+            Transaction {
+                kind: Kind::Deposit,
+                client,
+                transaction_id: transaction_id as TransactionId,
+                amount: Some(Decimal::from(10)),
+                currency: Currency::default(),
+                to_currency: None,
+                rate: None,
+                to_client: None,
+                timestamp: seconds.and_then(Timestamp::from_epoch_seconds),
+            },
+        );
+    }
+
+    let mut summaries = log
+        .replay(TransactionEngine::default(), "time:250".parse()?)?
+        .current_account_states();
+    summaries.sort_by_key(|summary| summary.client);
+    assert_eq!(
+        summaries
+            .iter()
+            .map(|summary| (summary.client, summary.total))
+            .collect::<Vec<_>>(),
+        vec![(1, Decimal::from(10)), (2, Decimal::from(10))]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reads_the_input() -> Result<()> {
+    use rust_decimal::Decimal;
+    use std::path::Path;
+
+    let log = EventLog::from_input(Input::open(Path::new("test_data/input_from_pdf.csv"))?)?;
+    assert_eq!(log.len(), 5);
+    assert_eq!(log.events()[0].line, 2);
+
+    // After the second deposit for client 1, and before their withdrawal
+    let summaries = log.balances_as_of(TransactionEngine::default(), AsOf::Line(4), 1)?;
+    assert_eq!(summaries[0].total, Decimal::from(3));
+
+    Ok(())
+}
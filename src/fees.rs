@@ -0,0 +1,185 @@
+//! Transaction fees
+//!
+//! Fees are loaded once at startup from a CSV in the following form:
+//!
+//! ```text
+//! type,       flat, percent, min,  max
+//! deposit,    0.10,     1.5, 0.20, 10
+//! withdrawal, 1.00,        ,     ,
+//! ```
+//!
+//! Only deposits and withdrawals can have fees. Every column but `type` is optional.
+
+use crate::prelude::*;
+use crate::transaction::Kind;
+use csv::{ReaderBuilder, Trim};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The number of decimal places a fee is rounded to
+pub const FEE_DECIMAL_PLACES: u32 = 4;
+
+#[derive(Default, Debug, Clone)]
+pub struct FeeSchedule {
+    fees: HashMap<Kind, Fee>,
+}
+
+/// The fee for a single kind of transaction
+#[derive(Default, Debug, Clone)]
+pub struct Fee {
+    /// Charged on every transaction
+    pub flat: Option<Decimal>,
+
+    /// Charged as a percentage of the amount, on top of the flat fee
+    pub percent: Option<Decimal>,
+
+    /// The smallest fee that is charged
+    pub min: Option<Decimal>,
+
+    /// The largest fee that is charged
+    pub max: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+struct FeeRow {
+    #[serde(rename = "type")]
+    kind: Kind,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    flat: Option<Decimal>,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    percent: Option<Decimal>,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    min: Option<Decimal>,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    max: Option<Decimal>,
+}
+
+impl FeeSchedule {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Could not open fees file {:?}", path))?;
+        Self::from_reader(file)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut schedule = Self::default();
+
+        for row in ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(reader)
+            .into_deserialize()
+        {
+            let row: FeeRow = row?;
+            schedule.insert(
+                row.kind,
+                Fee {
+                    flat: row.flat,
+                    percent: row.percent,
+                    min: row.min,
+                    max: row.max,
+                },
+            )?;
+        }
+
+        Ok(schedule)
+    }
+
+    pub fn insert(&mut self, kind: Kind, fee: Fee) -> Result<()> {
+        if !matches!(kind, Kind::Deposit | Kind::Withdrawal) {
+            return Err(anyhow!(
+                "Only deposits and withdrawals can have fees, not {:?}",
+                kind
+            ));
+        }
+
+        if let (Some(min), Some(max)) = (fee.min, fee.max) {
+            if min > max {
+                return Err(anyhow!(
+                    "The minimum fee for {:?} is more than the maximum: min={} max={}",
+                    kind,
+                    min,
+                    max
+                ));
+            }
+        }
+
+        self.fees.insert(kind, fee);
+        Ok(())
+    }
+
+    /// The fee charged for a transaction of the given kind and amount, or `None` if it's too
+    /// large to work out
+    pub fn fee(&self, kind: Kind, amount: Decimal) -> Option<Decimal> {
+        match self.fees.get(&kind) {
+            Some(fee) => fee.for_amount(amount),
+            None => Some(Decimal::ZERO),
+        }
+    }
+}
+
+impl Fee {
+    /// The flat and percentage fees together, clamped to the min and max and rounded to
+    /// [`FEE_DECIMAL_PLACES`] (half to even). A fee is never negative. `None` if the amount is
+    /// so large the fee overflows
+    pub fn for_amount(&self, amount: Decimal) -> Option<Decimal> {
+        let percentage = amount
+            .checked_mul(self.percent.unwrap_or_default())?
+            .checked_div(Decimal::ONE_HUNDRED)?;
+        let mut fee = self.flat.unwrap_or_default().checked_add(percentage)?;
+
+        if let Some(min) = self.min {
+            fee = fee.max(min);
+        }
+
+        if let Some(max) = self.max {
+            fee = fee.min(max);
+        }
+
+        Some(fee.max(Decimal::ZERO).round_dp(FEE_DECIMAL_PLACES))
+    }
+}
+
+#[test]
+fn fee_caps() -> Result<()> {
+    let schedule = FeeSchedule::from_reader(
+        r#"type,       flat, percent, min, max
+deposit,    0.10,       1,   0.5,   2
+withdrawal,    1,        ,      ,
+"#
+        .as_bytes(),
+    )?;
+
+    assert_eq!(
+        schedule.fee(Kind::Deposit, Decimal::from(10)),
+        Some(Decimal::from_str_exact("0.5")?)
+    );
+    assert_eq!(
+        schedule.fee(Kind::Deposit, Decimal::from(100)),
+        Some(Decimal::from_str_exact("1.1")?)
+    );
+    assert_eq!(
+        schedule.fee(Kind::Deposit, Decimal::from(1_000)),
+        Some(Decimal::from(2))
+    );
+    assert_eq!(
+        schedule.fee(Kind::Withdrawal, Decimal::from(1_000)),
+        Some(Decimal::ONE)
+    );
+    assert_eq!(
+        schedule.fee(Kind::Exchange, Decimal::from(1_000)),
+        Some(Decimal::ZERO)
+    );
+
+    // Too large to take a percentage of
+    let fee = Fee {
+        percent: Some(Decimal::from(150)),
+        ..Fee::default()
+    };
+    assert_eq!(fee.for_amount(Decimal::MAX), None);
+
+    Ok(())
+}
@@ -0,0 +1,435 @@
+//! Synthetic input
+//!
+//! A [`Generator`] makes a reproducible stream of transactions from a seed. Disputes are
+//! always of one of the client's earlier deposits, and resolves and chargebacks of one of
+//! their open disputes, so they exercise the engine rather than being ignored.
+
+use crate::prelude::*;
+use crate::transaction::{Currency, Kind, Transaction, TransactionId, UserId};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The number of decimal places generated amounts have
+pub const AMOUNT_DECIMAL_PLACES: u32 = 2;
+
+/// The largest mean amount, so that amounts, and the balances they add up to, can't overflow
+pub const MAX_MEAN_AMOUNT: Decimal = Decimal::from_parts(1_000_000_000, 0, 0, false, 0);
+
+/// How often each kind of transaction is generated, relative to the others
+///
+/// Parsed from, and displayed as, `kind=weight` pairs separated by commas, like
+/// `deposit=60,withdrawal=30`. Kinds that aren't listed are never generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KindMix {
+    weights: Vec<(Kind, u32)>,
+}
+
+impl Default for KindMix {
+    fn default() -> Self {
+        Self {
+            weights: vec![
+                (Kind::Deposit, 60),
+                (Kind::Withdrawal, 30),
+                (Kind::Dispute, 5),
+                (Kind::Resolve, 3),
+                (Kind::Chargeback, 2),
+            ],
+        }
+    }
+}
+
+impl KindMix {
+    fn total(&self) -> u32 {
+        self.weights.iter().map(|(_, weight)| weight).sum()
+    }
+
+    fn choose(&self, rng: &mut impl Rng) -> Kind {
+        let mut choice = rng.gen_range(0..self.total());
+
+        for (kind, weight) in &self.weights {
+            if choice < *weight {
+                return *kind;
+            }
+            choice -= weight;
+        }
+
+        unreachable!("The choice is less than the total weight")
+    }
+
+    fn includes(&self, kind: Kind) -> bool {
+        self.weights
+            .iter()
+            .any(|(other, weight)| *other == kind && *weight > 0)
+    }
+}
+
+impl FromStr for KindMix {
+    type Err = anyhow::Error;
+
+    fn from_str(mix: &str) -> Result<Self> {
+        let mut weights = Vec::new();
+
+        for pair in mix.split(',') {
+            let (kind, weight) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected kind=weight, got {:?}", pair))?;
+
+            let kind = match kind.trim() {
+                "deposit" => Kind::Deposit,
+                "withdrawal" => Kind::Withdrawal,
+                "dispute" => Kind::Dispute,
+                "resolve" => Kind::Resolve,
+                "chargeback" => Kind::Chargeback,
+                "transfer" => Kind::Transfer,
+                other => return Err(anyhow!("Cannot generate transactions of type {:?}", other)),
+            };
+
+            weights.push((kind, weight.trim().parse()?));
+        }
+
+        let mix = Self { weights };
+
+        if mix.total() == 0 {
+            return Err(anyhow!("At least one kind must have a weight"));
+        }
+
+        Ok(mix)
+    }
+}
+
+impl fmt::Display for KindMix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (kind, weight)) in self.weights.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", kind, weight)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How the amounts of deposits, withdrawals and transfers are spread around their mean
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmountDistribution {
+    /// Every amount is the mean
+    Fixed,
+
+    /// Evenly spread between the smallest amount and twice the mean
+    Uniform,
+
+    /// Mostly small amounts, with a long tail of large ones
+    #[default]
+    Exponential,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    pub clients: UserId,
+    pub transactions: usize,
+    pub mix: KindMix,
+    pub amounts: AmountDistribution,
+    pub mean_amount: Decimal,
+    pub seed: u64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            clients: 50,
+            transactions: 250_000,
+            mix: KindMix::default(),
+            amounts: AmountDistribution::default(),
+            mean_amount: Decimal::ONE_HUNDRED,
+            seed: 0,
+        }
+    }
+}
+
+/// A client's deposits that can be disputed, and their disputes that are still open
+#[derive(Default, Debug)]
+struct ClientHistory {
+    deposits: Vec<TransactionId>,
+    disputes: Vec<TransactionId>,
+}
+
+pub struct Generator {
+    config: GeneratorConfig,
+    rng: ChaCha8Rng,
+    generated: usize,
+
+    /// `None` once every transaction ID has been used
+    next_transaction_id: Option<TransactionId>,
+    clients: Vec<ClientHistory>,
+}
+
+impl Generator {
+    pub fn new(config: GeneratorConfig) -> Result<Self> {
+        if config.clients == 0 {
+            return Err(anyhow!("There must be at least one client"));
+        }
+
+        if config.mix.includes(Kind::Transfer) && config.clients < 2 {
+            return Err(anyhow!("Transfers need at least two clients"));
+        }
+
+        if config.mean_amount <= Decimal::ZERO || config.mean_amount > MAX_MEAN_AMOUNT {
+            return Err(anyhow!(
+                "The mean amount must be positive and at most {}, not {}",
+                MAX_MEAN_AMOUNT,
+                config.mean_amount
+            ));
+        }
+
+        // Each transaction takes at most one new ID
+        if config.transactions > TransactionId::MAX as usize {
+            return Err(anyhow!(
+                "At most {} transactions can be generated, not {}",
+                TransactionId::MAX,
+                config.transactions
+            ));
+        }
+
+        Ok(Self {
+            rng: ChaCha8Rng::seed_from_u64(config.seed),
+            clients: (0..config.clients)
+                .map(|_| ClientHistory::default())
+                .collect(),
+            config,
+            generated: 0,
+            next_transaction_id: Some(0),
+        })
+    }
+
+    /// Writes every transaction as an input CSV
+    pub fn write_csv<W: Write>(self, writer: W) -> Result<()> {
+        let transfers = self.config.mix.includes(Kind::Transfer);
+        let mut csv_writer = ::csv::Writer::from_writer(writer);
+
+        let mut header = vec!["type", "client", "tx", "amount"];
+        if transfers {
+            header.push("to_client");
+        }
+        csv_writer.write_record(&header)?;
+
+        for transaction in self {
+            let transaction = transaction?;
+            let mut record = vec![
+                transaction.kind.to_string(),
+                transaction.client.to_string(),
+                transaction.transaction_id.to_string(),
+                transaction
+                    .amount
+                    .map(|amount| amount.to_string())
+                    .unwrap_or_default(),
+            ];
+            if transfers {
+                record.push(
+                    transaction
+                        .to_client
+                        .map(|to_client| to_client.to_string())
+                        .unwrap_or_default(),
+                );
+            }
+            csv_writer.write_record(&record)?;
+        }
+
+        csv_writer.flush()?;
+        Ok(())
+    }
+
+    fn amount(&mut self) -> Result<Decimal> {
+        let smallest = Decimal::new(1, AMOUNT_DECIMAL_PLACES);
+        let mean = self.config.mean_amount;
+        let too_large = || anyhow!("Amounts with a mean of {} are too large to generate", mean);
+
+        let amount = match self.config.amounts {
+            AmountDistribution::Fixed => mean,
+            AmountDistribution::Uniform => {
+                let scale = Decimal::from(10u64.pow(AMOUNT_DECIMAL_PLACES));
+                let largest = mean
+                    .checked_mul(Decimal::TWO * scale)
+                    .ok_or_else(too_large)?
+                    .floor();
+                let largest = i64::try_from(largest).map_err(|_| too_large())?.max(1);
+                Decimal::new(self.rng.gen_range(1..=largest), AMOUNT_DECIMAL_PLACES)
+            }
+            AmountDistribution::Exponential => {
+                // Inverse transform sampling, from a uniform sample in (0, 1]
+                let sample = 1.0 - self.rng.gen::<f64>();
+                Decimal::try_from(-sample.ln())
+                    .unwrap_or_default()
+                    .checked_mul(mean)
+                    .ok_or_else(too_large)?
+            }
+        };
+
+        Ok(amount.round_dp(AMOUNT_DECIMAL_PLACES).max(smallest))
+    }
+
+    fn new_transaction_id(&mut self) -> Result<TransactionId> {
+        let transaction_id = self
+            .next_transaction_id
+            .ok_or_else(|| anyhow!("Every transaction ID has been used"))?;
+        self.next_transaction_id = transaction_id.checked_add(1);
+        Ok(transaction_id)
+    }
+
+    fn transaction(&mut self, kind: Kind) -> Result<Transaction> {
+        let client = self.rng.gen_range(0..self.config.clients);
+        let history = &mut self.clients[usize::from(client)];
+
+        let (kind, transaction_id, amount, to_client) = match kind {
+            Kind::Dispute => match take(&mut history.deposits, &mut self.rng) {
+                Some(deposit) => {
+                    history.disputes.push(deposit);
+                    (kind, deposit, None, None)
+                }
+                None => return self.transaction(Kind::Deposit),
+            },
+            Kind::Resolve | Kind::Chargeback => match take(&mut history.disputes, &mut self.rng) {
+                Some(dispute) => {
+                    // A resolved deposit can be disputed again
+                    if kind == Kind::Resolve {
+                        history.deposits.push(dispute);
+                    }
+                    (kind, dispute, None, None)
+                }
+                None => return self.transaction(Kind::Deposit),
+            },
+            Kind::Deposit => {
+                let transaction_id = self.new_transaction_id()?;
+                self.clients[usize::from(client)]
+                    .deposits
+                    .push(transaction_id);
+                (kind, transaction_id, Some(self.amount()?), None)
+            }
+            Kind::Withdrawal => (kind, self.new_transaction_id()?, Some(self.amount()?), None),
+            Kind::Exchange => unreachable!("Exchanges are never in the mix"),
+            Kind::Transfer => {
+                // Any client but this one
+                let to_client =
+                    (client + self.rng.gen_range(1..self.config.clients)) % self.config.clients;
+                (
+                    kind,
+                    self.new_transaction_id()?,
+                    Some(self.amount()?),
+                    Some(to_client),
+                )
+            }
+        };
+
+        Ok(Transaction {
+            kind,
+            client,
+            transaction_id,
+            amount,
+            currency: Currency::default(),
+            to_currency: None,
+            rate: None,
+            to_client,
+            timestamp: None,
+        })
+    }
+}
+
+/// Takes a random transaction ID out of a list
+fn take(ids: &mut Vec<TransactionId>, rng: &mut ChaCha8Rng) -> Option<TransactionId> {
+    (!ids.is_empty()).then(|| ids.swap_remove(rng.gen_range(0..ids.len())))
+}
+
+impl Iterator for Generator {
+    type Item = Result<Transaction>;
+
+    fn next(&mut self) -> Option<Result<Transaction>> {
+        if self.generated == self.config.transactions {
+            return None;
+        }
+
+        self.generated += 1;
+        let kind = self.config.mix.choose(&mut self.rng);
+        Some(self.transaction(kind))
+    }
+}
+
+#[test]
+fn generated_disputes_are_of_deposits() -> Result<()> {
+    let config = GeneratorConfig {
+        clients: 5,
+        transactions: 2_000,
+        mix: "deposit=5,withdrawal=2,dispute=3,resolve=1,chargeback=1,transfer=1".parse()?,
+        ..GeneratorConfig::default()
+    };
+
+    let transactions: Vec<Transaction> = Generator::new(config.clone())?.collect::<Result<_>>()?;
+    assert_eq!(transactions.len(), 2_000);
+
+    for (i, transaction) in transactions.iter().enumerate() {
+        if let Kind::Dispute | Kind::Resolve | Kind::Chargeback = transaction.kind {
+            assert!(transactions[..i].iter().any(|earlier| {
+                earlier.kind == Kind::Deposit
+                    && earlier.client == transaction.client
+                    && earlier.transaction_id == transaction.transaction_id
+            }));
+        }
+    }
+
+    // The same seed makes the same CSV
+    let mut first = Vec::new();
+    let mut second = Vec::new();
+    Generator::new(config.clone())?.write_csv(&mut first)?;
+    Generator::new(config)?.write_csv(&mut second)?;
+    assert_eq!(first, second);
+
+    assert!("deposit=1,exchange=1".parse::<KindMix>().is_err());
+    assert_eq!(
+        KindMix::default().to_string().parse::<KindMix>()?,
+        KindMix::default()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn amounts_must_fit() -> Result<()> {
+    for mean_amount in [
+        Decimal::ZERO,
+        -Decimal::ONE,
+        MAX_MEAN_AMOUNT + Decimal::ONE,
+        Decimal::MAX,
+    ] {
+        let config = GeneratorConfig {
+            mean_amount,
+            ..GeneratorConfig::default()
+        };
+        assert!(Generator::new(config).is_err());
+    }
+
+    // The largest amounts the generator makes still fit
+    for amounts in [
+        AmountDistribution::Fixed,
+        AmountDistribution::Uniform,
+        AmountDistribution::Exponential,
+    ] {
+        let config = GeneratorConfig {
+            transactions: 1_000,
+            amounts,
+            mean_amount: MAX_MEAN_AMOUNT,
+            ..GeneratorConfig::default()
+        };
+        Generator::new(config)?.collect::<Result<Vec<_>>>()?;
+    }
+
+    // Running out of IDs is an error, rather than an overflow
+    let mut generator = Generator::new(GeneratorConfig::default())?;
+    generator.next_transaction_id = Some(TransactionId::MAX);
+    assert_eq!(generator.new_transaction_id()?, TransactionId::MAX);
+    assert!(generator.new_transaction_id().is_err());
+
+    Ok(())
+}
@@ -0,0 +1,283 @@
+//! Where input CSV comes from
+//!
+//! Files are memory mapped, so their lines are read out of the map without being copied
+//! into a `String` each. The CSV reader still copies each line into its own buffer to parse
+//! it. Stdin, given as `-`, and gzip compressed files can't be mapped, so they're streamed
+//! through a buffer instead.
+
+use crate::csv::{CsvParser, LikelyValidLine};
+use crate::prelude::*;
+use flate2::bufread::MultiGzDecoder;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{stdin, BufRead, BufReader, Read};
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The first bytes of every gzip file
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub enum Input {
+    Mapped(Arc<Mmap>),
+    Stream(Box<dyn BufRead + Send>),
+}
+
+impl Input {
+    /// Maps the file at `path` if it can, or streams it if it's `-` for stdin, gzip
+    /// compressed, or not a regular file
+    ///
+    /// A mapped file must not be truncated or written to until the input is dropped.
+    pub fn open(path: &Path) -> Result<Self> {
+        if path == Path::new("-") {
+            info!("Reading from stdin");
+            return Ok(Self::Stream(Box::new(BufReader::new(stdin()))));
+        }
+
+        let mut reader = BufReader::new(File::open(path)?);
+
+        // Recognised by what's in it, rather than by its name
+        if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            info!("Decompressing gzip input");
+            return Ok(Self::Stream(Box::new(BufReader::new(MultiGzDecoder::new(
+                reader,
+            )))));
+        }
+
+        // Pipes, devices and the like can change under a map without warning, or can't be
+        // mapped at all
+        if !reader.get_ref().metadata()?.is_file() {
+            return Ok(Self::Stream(Box::new(reader)));
+        }
+
+        // Safety: the file must not be truncated or written to while it's mapped. Reading a
+        // page that truncation took away raises SIGBUS, and writes change bytes that are
+        // borrowed as immutable. Nothing here can stop another process doing either, so that's
+        // left to whoever provides the input, as documented above
+        match unsafe { Mmap::map(reader.get_ref()) } {
+            Ok(map) => Ok(Self::Mapped(Arc::new(map))),
+            Err(error) => {
+                // Like pipes and other special files
+                warn!("Could not map {:?}, so streaming it: {}", path, error);
+                Ok(Self::Stream(Box::new(reader)))
+            }
+        }
+    }
+
+    /// How many bytes there are to read, if that's known before reading them
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Self::Mapped(map) => Some(map.len() as u64),
+            Self::Stream(_) => None,
+        }
+    }
+
+    /// Reads the header, returning it along with the rest of the lines
+    pub fn lines(self) -> Result<(LikelyValidLine, Lines)> {
+        let (header_line, source) = match self {
+            Self::Mapped(map) => {
+                let mut rest = &map[..];
+                let header_line = read_header(&mut rest)?;
+                let offset = map.len() - rest.len();
+                (header_line, Source::Mapped(map, offset))
+            }
+            Self::Stream(mut reader) => (
+                read_header(&mut reader)?,
+                Source::Stream(reader, Vec::new()),
+            ),
+        };
+
+        Ok((
+            header_line,
+            Lines {
+                source,
+                line_number: 1,
+            },
+        ))
+    }
+}
+
+enum Source {
+    /// The map, and how far into it has been read
+    Mapped(Arc<Mmap>, usize),
+
+    /// The stream, and a buffer for the current line
+    Stream(Box<dyn BufRead + Send>, Vec<u8>),
+}
+
+/// The lines of an [`Input`] after its header, without their line endings
+pub struct Lines {
+    source: Source,
+    line_number: usize,
+}
+
+impl Lines {
+    /// The next line and its line number, where the header is line 1
+    pub fn next_line(&mut self) -> Result<Option<(usize, &[u8])>> {
+        let line = match &mut self.source {
+            Source::Mapped(map, offset) => {
+                let rest = &map[*offset..];
+                if rest.is_empty() {
+                    return Ok(None);
+                }
+
+                let line = match rest.iter().position(|&byte| byte == b'\n') {
+                    Some(end) => &rest[..=end],
+                    None => rest,
+                };
+                *offset += line.len();
+                line
+            }
+            Source::Stream(reader, buffer) => {
+                buffer.clear();
+                if reader.read_until(b'\n', buffer)? == 0 {
+                    return Ok(None);
+                }
+                &buffer[..]
+            }
+        };
+
+        self.line_number += 1;
+        Ok(Some((self.line_number, trim_line_ending(line))))
+    }
+
+    /// The next whole lines adding up to at least `size` bytes, or to the end of the input,
+    /// and the line number of the first
+    pub fn next_chunk(&mut self, size: usize) -> Result<Option<(usize, Chunk)>> {
+        let chunk = match &mut self.source {
+            Source::Mapped(map, offset) => {
+                let start = *offset;
+                if start == map.len() {
+                    return Ok(None);
+                }
+
+                let mut end = std::cmp::min(start + size, map.len());
+                if map[end - 1] != b'\n' {
+                    end = match map[end..].iter().position(|&byte| byte == b'\n') {
+                        Some(newline) => end + newline + 1,
+                        None => map.len(),
+                    };
+                }
+                *offset = end;
+
+                Chunk::Mapped(Arc::clone(map), start..end)
+            }
+            Source::Stream(reader, _) => {
+                let mut chunk = Vec::with_capacity(size);
+                reader.take(size as u64).read_to_end(&mut chunk)?;
+
+                if chunk.is_empty() {
+                    return Ok(None);
+                }
+
+                if chunk.last() != Some(&b'\n') {
+                    reader.read_until(b'\n', &mut chunk)?;
+                }
+
+                Chunk::Copied(chunk)
+            }
+        };
+
+        let first_line_number = self.line_number + 1;
+        self.line_number += lines(&chunk).count();
+        Ok(Some((first_line_number, chunk)))
+    }
+}
+
+/// Whole lines of input, either part of a memory map or copied out of a stream
+pub enum Chunk {
+    Mapped(Arc<Mmap>, Range<usize>),
+    Copied(Vec<u8>),
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mapped(map, range) => &map[range.clone()],
+            Self::Copied(bytes) => bytes,
+        }
+    }
+}
+
+/// Reads the header line, which every input must have
+fn read_header(reader: &mut impl BufRead) -> Result<LikelyValidLine> {
+    let mut header_line = String::new();
+    reader.read_line(&mut header_line)?;
+
+    CsvParser::valid_line(header_line.trim_end_matches(['\n', '\r']).to_string()).ok_or_else(
+        || {
+            anyhow!(
+                "There must be at least 1 line in the provided CSV, a header line and 0 or more data lines."
+            )
+        },
+    )
+}
+
+/// Splits bytes into lines the same way [`str::lines`] does
+pub fn lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes
+        .split_inclusive(|&byte| byte == b'\n')
+        .map(trim_line_ending)
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    match line.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None => line,
+    }
+}
+
+#[test]
+fn lines_match_str_lines() {
+    for text in ["", "\n", "a", "a\n", "a\r\nb", "a\n\nb\r\n", "a\rb\n\r"] {
+        let expected: Vec<&[u8]> = text.lines().map(str::as_bytes).collect();
+        assert_eq!(lines(text.as_bytes()).collect::<Vec<_>>(), expected);
+    }
+}
+
+#[test]
+fn mapped_streamed_and_compressed_inputs_agree() -> Result<()> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let text = "type,client,tx,amount\ndeposit,1,1,1.0\n\nwithdrawal,1,2,0.5\r\ndeposit,2,3,2.0";
+    let directory = std::env::temp_dir().join(format!("payment_engine-{}", std::process::id()));
+    std::fs::create_dir_all(&directory)?;
+
+    let plain = directory.join("input.csv");
+    std::fs::write(&plain, text)?;
+    let compressed = directory.join("input.csv.gz");
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::fast());
+    encoder.write_all(text.as_bytes())?;
+    encoder.finish()?;
+
+    let mapped = Input::open(&plain)?;
+    assert!(matches!(mapped, Input::Mapped(_)));
+    let streamed = Input::Stream(Box::new(text.as_bytes()));
+    let decompressed = Input::open(&compressed)?;
+    assert!(matches!(decompressed, Input::Stream(_)));
+
+    for input in [mapped, streamed, decompressed] {
+        let (_, mut lines) = input.lines()?;
+        let mut actual = Vec::new();
+        while let Some((line_number, line)) = lines.next_line()? {
+            actual.push((line_number, String::from_utf8(line.to_vec())?));
+        }
+
+        assert_eq!(
+            actual,
+            vec![
+                (2, "deposit,1,1,1.0".to_string()),
+                (3, String::new()),
+                (4, "withdrawal,1,2,0.5".to_string()),
+                (5, "deposit,2,3,2.0".to_string()),
+            ]
+        );
+    }
+
+    std::fs::remove_dir_all(directory)?;
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! A per-transaction ledger, or journal
+//!
+//! The engine sends an entry for every movement of a client's funds, and the entries are
+//! written out as they arrive on a separate thread. Each entry has the client's balance in
+//! that currency before and after, so every movement can be reconciled.
+
+use crate::prelude::*;
+use crate::transaction::{Currency, Kind, Timestamp, TransactionId, UserId};
+use crate::transaction_engine::Balance;
+use ::csv::Writer;
+use flume::{bounded, Sender};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::thread::JoinHandle;
+
+/// How the ledger is written
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerFormat {
+    /// CSV with a header
+    #[default]
+    Csv,
+
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// A movement of a client's funds in a single currency
+///
+/// Most transactions move a single currency. Exchanges have an entry for each currency.
+#[derive(Serialize, Debug, Clone)]
+pub struct LedgerEntry {
+    pub client: UserId,
+
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionId,
+
+    #[serde(rename = "type")]
+    pub kind: Kind,
+
+    pub currency: Currency,
+
+    /// The amount of the transaction in this currency, or the amount disputed for disputes,
+    /// resolves and chargebacks
+    pub amount: Decimal,
+
+    /// The fee charged to the client. Fees refunded on chargeback are negative
+    pub fee: Decimal,
+
+    pub available_before: Decimal,
+    pub held_before: Decimal,
+    pub total_before: Decimal,
+
+    pub available_after: Decimal,
+    pub held_after: Decimal,
+    pub total_after: Decimal,
+
+    pub timestamp: Option<Timestamp>,
+}
+
+impl LedgerEntry {
+    pub fn new(
+        client: UserId,
+        transaction_id: TransactionId,
+        kind: Kind,
+        timestamp: Option<Timestamp>,
+    ) -> Self {
+        Self {
+            client,
+            transaction_id,
+            kind,
+            currency: Currency::default(),
+            amount: Decimal::ZERO,
+            fee: Decimal::ZERO,
+            available_before: Decimal::ZERO,
+            held_before: Decimal::ZERO,
+            total_before: Decimal::ZERO,
+            available_after: Decimal::ZERO,
+            held_after: Decimal::ZERO,
+            total_after: Decimal::ZERO,
+            timestamp,
+        }
+    }
+
+    pub fn movement(mut self, currency: Currency, amount: Decimal, fee: Decimal) -> Self {
+        self.currency = currency;
+        self.amount = amount;
+        self.fee = fee;
+        self
+    }
+
+    pub fn balances(mut self, before: &Balance, after: &Balance) -> Self {
+        self.available_before = before.available();
+        self.held_before = before.held();
+        self.total_before = before.total();
+        self.available_after = after.available();
+        self.held_after = after.held();
+        self.total_after = after.total();
+        self
+    }
+}
+
+/// Writes the ledger to a file, returning where to send entries and the writer's thread
+///
+/// The thread finishes once every sender has been dropped.
+pub fn spawn_writer(
+    path: &Path,
+    format: LedgerFormat,
+    queue_depth: usize,
+) -> Result<(Sender<LedgerEntry>, JoinHandle<Result<()>>)> {
+    let file =
+        File::create(path).with_context(|| format!("Could not create ledger file {:?}", path))?;
+    let (sender, receiver) = bounded::<LedgerEntry>(queue_depth);
+
+    let writer = std::thread::spawn(move || {
+        match format {
+            LedgerFormat::Csv => {
+                let mut csv_writer = Writer::from_writer(file);
+
+                for entry in receiver {
+                    csv_writer.serialize(entry)?;
+                }
+
+                csv_writer.flush()?;
+            }
+            LedgerFormat::Jsonl => {
+                let mut writer = BufWriter::new(file);
+
+                for entry in receiver {
+                    serde_json::to_writer(&mut writer, &entry)?;
+                    writer.write_all(b"\n")?;
+                }
+
+                writer.flush()?;
+            }
+        }
+
+        Ok(())
+    });
+
+    Ok((sender, writer))
+}
@@ -0,0 +1,22 @@
+//! A toy transaction engine
+//!
+//! Takes a series of transactions and keeps the resulting state of every client's account.
+//! The CLI in `main.rs` is a thin layer over this library.
+
+pub mod accounts;
+pub mod csv;
+pub mod event_log;
+pub mod fees;
+pub mod generate;
+pub mod input;
+pub mod ledger;
+pub mod limits;
+pub mod metrics;
+pub mod pipeline;
+pub mod prelude;
+pub mod rates;
+pub mod sharding;
+pub mod task_pool;
+pub mod transaction;
+pub mod transaction_engine;
+pub mod verify;
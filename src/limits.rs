@@ -0,0 +1,250 @@
+//! Withdrawal limits
+//!
+//! Limits are loaded once at startup from a CSV in the following form:
+//!
+//! ```text
+//! client, max_withdrawal, max_withdrawals, window, window_secs, max_daily_outflow
+//!       ,           1000,               3,     10,            ,              2500
+//!     42,          50000,                ,       ,        3600,
+//! ```
+//!
+//! A row without a client sets the limits for every client. Rows with a client override
+//! those limits for that client, and fall back to them for any column left empty.
+//!
+//! `window_secs` and `max_daily_outflow` are limits in time, so they depend on the optional
+//! timestamp column (see [`Timestamp`]). Without timestamps, `window_secs` never lets a
+//! withdrawal leave its window, and `max_daily_outflow` rejects every debit. Only
+//! `max_withdrawal`, `max_withdrawals` and `window` work on input without them.
+
+use crate::prelude::*;
+use crate::transaction::{Timestamp, UserId};
+use csv::{ReaderBuilder, Trim};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Default, Debug, Clone)]
+pub struct LimitsConfig {
+    default: Limits,
+    overrides: HashMap<UserId, Limits>,
+}
+
+/// The limits for a single client. Unset limits are not enforced
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Limits {
+    /// The largest amount a single withdrawal can be
+    pub max_withdrawal: Option<Decimal>,
+
+    /// The most withdrawals allowed within the window
+    pub max_withdrawals: Option<usize>,
+
+    /// How many of the client's most recent transactions `max_withdrawals` applies to,
+    /// including the withdrawal being checked
+    pub window: Option<usize>,
+
+    /// How many seconds before the withdrawal being checked `max_withdrawals` applies to
+    ///
+    /// Withdrawals count towards `max_withdrawals` while they're within every window that is
+    /// set. Without any windows, all of the client's withdrawals count.
+    pub window_secs: Option<i64>,
+
+    /// The most that can leave the client's account in each currency in a UTC calendar day,
+    /// through withdrawals, transfers and exchanges, not counting fees
+    ///
+    /// Days come from the timestamp column, so while this is set a debit is rejected if
+    /// neither it nor any of the client's earlier transactions has a timestamp.
+    pub max_daily_outflow: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+struct LimitsRow {
+    #[serde(default)]
+    client: Option<UserId>,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    max_withdrawal: Option<Decimal>,
+    #[serde(default)]
+    max_withdrawals: Option<usize>,
+    #[serde(default)]
+    window: Option<usize>,
+    #[serde(default)]
+    window_secs: Option<i64>,
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    max_daily_outflow: Option<Decimal>,
+}
+
+/// Why a withdrawal went over a client's limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// The withdrawal is larger than `max_withdrawal`
+    WithdrawalAmount,
+
+    /// The client has already made `max_withdrawals` withdrawals within the window
+    WithdrawalCount,
+
+    /// The debit would take the client over `max_daily_outflow` for the day
+    DailyOutflow,
+
+    /// There's no telling which day the debit is on for `max_daily_outflow`, as it has no
+    /// timestamp
+    UndatedOutflow,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WithdrawalAmount => f.write_str("withdrawal amount limit exceeded"),
+            Self::WithdrawalCount => f.write_str("withdrawal count limit exceeded"),
+            Self::DailyOutflow => f.write_str("daily outflow limit exceeded"),
+            Self::UndatedOutflow => f.write_str("daily outflow limit needs a timestamp"),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Could not open limits file {:?}", path))?;
+        Self::from_reader(file)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut config = Self::default();
+
+        for row in ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(reader)
+            .into_deserialize()
+        {
+            let row: LimitsRow = row?;
+            let limits = Limits {
+                max_withdrawal: row.max_withdrawal,
+                max_withdrawals: row.max_withdrawals,
+                window: row.window,
+                window_secs: row.window_secs,
+                max_daily_outflow: row.max_daily_outflow,
+            };
+
+            match row.client {
+                Some(client) => {
+                    config.overrides.insert(client, limits);
+                }
+                None => config.default = limits,
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The limits for a client, after applying any overrides
+    pub fn limits_for(&self, client: UserId) -> Limits {
+        match self.overrides.get(&client) {
+            Some(overrides) => Limits {
+                max_withdrawal: overrides.max_withdrawal.or(self.default.max_withdrawal),
+                max_withdrawals: overrides.max_withdrawals.or(self.default.max_withdrawals),
+                window: overrides.window.or(self.default.window),
+                window_secs: overrides.window_secs.or(self.default.window_secs),
+                max_daily_outflow: overrides
+                    .max_daily_outflow
+                    .or(self.default.max_daily_outflow),
+            },
+            None => self.default,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks a withdrawal against the limits on withdrawals alone
+    ///
+    /// `recent_withdrawals` is how many withdrawals the client made within the window, not
+    /// counting this one.
+    pub fn check_withdrawal(
+        &self,
+        amount: Decimal,
+        recent_withdrawals: usize,
+    ) -> Result<(), LimitExceeded> {
+        if let Some(max_withdrawal) = self.max_withdrawal {
+            if amount > max_withdrawal {
+                return Err(LimitExceeded::WithdrawalAmount);
+            }
+        }
+
+        if let Some(max_withdrawals) = self.max_withdrawals {
+            if recent_withdrawals >= max_withdrawals {
+                return Err(LimitExceeded::WithdrawalCount);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks any debit against `max_daily_outflow`, where `outflow_today` is how much already
+    /// left the client's account in the same currency that day
+    pub fn check_outflow(
+        &self,
+        amount: Decimal,
+        outflow_today: Decimal,
+    ) -> Result<(), LimitExceeded> {
+        if let Some(max_daily_outflow) = self.max_daily_outflow {
+            if outflow_today + amount > max_daily_outflow {
+                return Err(LimitExceeded::DailyOutflow);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a withdrawal that was the client's `position`th transaction, made at `then`,
+    /// still counts towards `max_withdrawals` for one being made at `now` after `applied`
+    /// transactions
+    pub fn within_window(
+        &self,
+        position: usize,
+        then: Option<Timestamp>,
+        applied: usize,
+        now: Option<Timestamp>,
+    ) -> bool {
+        if let Some(window) = self.window {
+            if position + window <= applied {
+                return false;
+            }
+        }
+
+        if let (Some(window_secs), Some(then), Some(now)) = (self.window_secs, then, now) {
+            if now.seconds_since(then) >= window_secs {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[test]
+fn client_overrides() -> Result<()> {
+    let config = LimitsConfig::from_reader(
+        r#"client, max_withdrawal, max_withdrawals, window
+     7,            500,                ,
+      ,            100,               2,      5
+"#
+        .as_bytes(),
+    )?;
+
+    let limits = config.limits_for(7);
+    assert_eq!(limits.max_withdrawal, Some(Decimal::from(500)));
+    assert_eq!(limits.max_withdrawals, Some(2));
+    assert_eq!(limits.check_withdrawal(Decimal::from(200), 1), Ok(()));
+    assert_eq!(
+        config.limits_for(8).check_withdrawal(Decimal::from(200), 0),
+        Err(LimitExceeded::WithdrawalAmount)
+    );
+    assert_eq!(
+        config.limits_for(8).check_withdrawal(Decimal::from(50), 2),
+        Err(LimitExceeded::WithdrawalCount)
+    );
+
+    Ok(())
+}
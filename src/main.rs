@@ -1,72 +1,186 @@
-use crate::csv::CsvParser;
-use crate::prelude::*;
-use crate::transaction_engine::TransactionEngine;
-use ::csv::Writer;
-use anyhow::Context;
 use clap::Parser;
-use cli::Args;
-use flume::{bounded, Sender};
-use std::collections::HashMap;
+use cli::{Args, Command, GenerateArgs};
+use csv::Writer;
+use payment_engine::csv::CsvParser;
+use payment_engine::event_log::{Cutoff, Position};
+use payment_engine::fees::FeeSchedule;
+use payment_engine::generate::{Generator, GeneratorConfig};
+use payment_engine::input::Input;
+use payment_engine::ledger;
+use payment_engine::limits::LimitsConfig;
+use payment_engine::metrics::Metrics;
+use payment_engine::pipeline::{ParallelParser, CHUNK_SIZE};
+use payment_engine::prelude::*;
+use payment_engine::rates::RateTable;
+use payment_engine::task_pool::{OnFailure, TaskPool, WorkerFailure};
+use payment_engine::transaction_engine::TransactionEngine;
+use payment_engine::verify;
 use std::fs::File;
-use std::io::{stdout, Write};
+use std::io::{stdout, BufWriter, Write};
 use std::sync::Arc;
-use tokio::fs::File as AsyncFile;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::task::JoinHandle;
-use transaction::Transaction;
+use std::time::Duration;
 
 mod cli;
-mod csv;
-mod prelude;
 mod setup;
-mod transaction;
-mod transaction_engine;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+/// The exit code when the output leaves out the clients of failed workers
+const PARTIAL_OUTPUT_EXIT_CODE: i32 = 2;
+
+/// Whether every client made it into the output
+#[derive(PartialEq, Eq)]
+enum Output {
+    Complete,
+    Partial,
+}
+
+fn main() -> Result<()> {
     let args = Args::parse();
 
-    setup::setup()?;
+    setup::setup(&args)?;
 
-    run(args).await?;
+    match args.command {
+        Some(Command::Generate(generate_args)) => generate(generate_args)?,
+        None => {
+            if run(args)? == Output::Partial {
+                std::process::exit(PARTIAL_OUTPUT_EXIT_CODE);
+            }
+        }
+    }
 
     Ok(())
 }
 
-async fn run(args: Args) -> Result<()> {
-    info!("Processing from {:?}", args.input_csv);
+fn generate(args: GenerateArgs) -> Result<()> {
+    let generator = Generator::new(GeneratorConfig {
+        clients: args.clients,
+        transactions: args.transactions,
+        mix: args.mix,
+        amounts: args.amounts,
+        mean_amount: args.mean_amount,
+        seed: args.seed,
+    })?;
 
-    let reader = BufReader::new(AsyncFile::open(args.input_csv).await?);
-    let mut reader = reader.lines();
+    info!(
+        "Generating {} transactions for {} clients",
+        args.transactions, args.clients
+    );
 
-    let header_line = reader.next_line().await?.ok_or_else(|| {
-        anyhow!(
-        "There must be at least 1 line in the provided CSV, a header line and 0 or more data lines."
-    )
-    })?;
+    match &args.output_csv {
+        Some(path) => {
+            info!("Outputting to {:?}", path);
+            generator.write_csv(BufWriter::new(File::create(path)?))
+        }
+        None => generator.write_csv(stdout().lock()),
+    }
+}
 
-    let mut csv_parser = CsvParser::new(CsvParser::valid_line(header_line).context("context")?)?;
+fn run(args: Args) -> Result<Output> {
+    let input_csv = args
+        .input_csv
+        .as_ref()
+        .ok_or_else(|| anyhow!("An input CSV is required"))?;
 
-    let engine = TransactionEngine::default();
+    info!("Processing from {:?}", input_csv);
 
-    let task_pool = TaskPool::new(engine, args.queue_depth, args.workers);
+    let input = Input::open(input_csv)?;
+    let sync = args.mode.is_sync(args.workers, input.size());
 
-    while let Some(line) = reader.next_line().await? {
-        match CsvParser::valid_line(line) {
-            Some(valid_line) => {
-                let transaction = csv_parser.line_to_transaction(valid_line)?;
-                task_pool.add_transaction(transaction).await?;
-            }
-            None => {
-                warn!("Get an invalid line, skipping");
-            }
-        }
+    let mut engine = TransactionEngine::default();
+
+    if let Some(path) = &args.rates {
+        info!("Loading exchange rates from {:?}", path);
+        engine = engine.with_rates(RateTable::from_path(path)?);
     }
 
-    let engine = task_pool.wait().await?;
+    if let Some(path) = &args.fees {
+        info!("Loading fees from {:?}", path);
+        engine = engine.with_fees(FeeSchedule::from_path(path)?);
+    }
+
+    if let Some(path) = &args.limits {
+        info!("Loading withdrawal limits from {:?}", path);
+        engine = engine.with_limits(LimitsConfig::from_path(path)?);
+    }
+
+    engine = engine.with_timestamp_policy(args.timestamp_policy);
+
+    if let Some(days) = args.dispute_window_days {
+        engine = engine.with_dispute_window_days(days);
+    }
+
+    let ledger_writer = match &args.ledger {
+        Some(path) => {
+            info!("Writing ledger to {:?}", path);
+            let (ledger, ledger_writer) =
+                ledger::spawn_writer(path, args.ledger_format, args.queue_depth)?;
+            engine = engine.with_ledger(ledger);
+            Some(ledger_writer)
+        }
+        None => None,
+    };
+
+    let metrics = if args.metrics_address.is_some() || args.metrics_file.is_some() {
+        let metrics = Arc::new(Metrics::new()?);
+        if let Some(address) = args.metrics_address {
+            metrics.serve(address)?;
+        }
+        engine = engine.with_metrics(Arc::clone(&metrics));
+        Some(metrics)
+    } else {
+        None
+    };
+
+    let (engine, failures) = if sync {
+        info!("Processing on a single thread");
+        (
+            process_sync(&args, input, engine, metrics.as_deref())?,
+            Vec::new(),
+        )
+    } else {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(process_async(&args, input, engine, metrics.clone()))?
+    };
+
+    if let (Some(metrics), Some(path)) = (&metrics, &args.metrics_file) {
+        info!("Writing metrics to {:?}", path);
+        metrics.write_to(path)?;
+    }
 
     let user_summaries = engine.current_account_states();
 
+    for (currency, fees) in engine.fees_collected() {
+        info!("Collected {} {} in fees", fees, currency);
+    }
+
+    for trial_balance in engine.trial_balance() {
+        if trial_balance.is_balanced() {
+            info!(
+                "The books balance in {}, with {} in debits and credits",
+                trial_balance.currency, trial_balance.debits
+            );
+        } else {
+            error!(
+                "The books don't balance in {}! debits={} credits={}",
+                trial_balance.currency, trial_balance.debits, trial_balance.credits
+            );
+        }
+    }
+
+    if args.verify {
+        verify::verify(&engine)?;
+        info!("Verified every client's balances and the books");
+    }
+
+    // The engine holds the ledger's sender, so the writer only finishes once it's gone
+    drop(engine);
+    if let Some(ledger_writer) = ledger_writer {
+        ledger_writer
+            .join()
+            .map_err(|_| anyhow!("Ledger writer panicked"))??;
+    }
+
     // Why have this be synchronous when io before has been async? Because we no longer
     // benefit from doing anything concurrently. Honestly,
     // choosing to do this asynchronously has been purely to show an ability to
@@ -92,82 +206,143 @@ async fn run(args: Args) -> Result<()> {
         csv_writer.serialize(summary)?;
     }
 
-    Ok(())
-}
+    if failures.is_empty() {
+        return Ok(Output::Complete);
+    }
+
+    for failure in failures {
+        if failure.client_lost {
+            error!("Left out client {}, as {}", failure.client, failure);
+        } else {
+            error!(
+                "Left out the clients on worker {}, as {}",
+                failure.worker, failure
+            );
+        }
+    }
 
-pub struct TaskPool {
-    parallelism: usize,
-    senders: HashMap<usize, Sender<transaction::Transaction>>,
-    tasks: Vec<JoinHandle<Result<()>>>,
-    engine: Arc<TransactionEngine>,
+    Ok(Output::Partial)
 }
 
-impl TaskPool {
-    pub fn new(engine: TransactionEngine, queue_depth: usize, num_workers: isize) -> Self {
-        let system_parallelism = std::thread::available_parallelism()
-            .expect("Required parallel capable environment")
-            .get();
-
-        let parallelism = if num_workers < 0 {
-            // subtract from total available
-            std::cmp::max(1, (system_parallelism as isize) + num_workers) as usize
-        } else if num_workers == 0 {
-            // set to available
-            system_parallelism
-        } else {
-            // set to num
-            num_workers as usize
+/// Reads, parses and applies every transaction in turn, on this thread
+fn process_sync(
+    args: &Args,
+    input: Input,
+    mut engine: TransactionEngine,
+    metrics: Option<&Metrics>,
+) -> Result<TransactionEngine> {
+    let (header_line, mut lines) = input.lines()?;
+    let mut csv_parser = CsvParser::new(header_line)?;
+    let mut cutoff = args.as_of.map(Cutoff::new);
+
+    while let Some((line_number, line)) = lines.next_line()? {
+        let transaction = match CsvParser::valid_bytes(line)
+            .map(|valid_line| csv_parser.bytes_to_transaction(valid_line))
+        {
+            Some(Ok(transaction)) => transaction,
+            Some(Err(error)) => {
+                if let Some(metrics) = metrics {
+                    metrics.parse_error();
+                }
+                return Err(error.context(format!("On line {}", line_number)));
+            }
+            None => {
+                if let Some(metrics) = metrics {
+                    metrics.parse_error();
+                }
+                warn!("Get an invalid line, skipping");
+                continue;
+            }
         };
 
-        info!("Using {parallelism} engine worker threads");
-
-        let mut senders = HashMap::new();
-        let mut tasks = Vec::new();
-        let engine = Arc::new(engine);
-
-        for i in 0..(parallelism) {
-            let (sender, recv) = bounded(queue_depth);
-            tasks.push(tokio::task::spawn_blocking({
-                let engine = Arc::clone(&engine);
-                move || {
-                    for transaction in recv {
-                        engine.add_transaction(transaction)?;
-                    }
-                    Ok(())
-                }
-            }));
-            senders.insert(i, sender);
+        let position = cutoff
+            .as_mut()
+            .map(|cutoff| cutoff.position(line_number, &transaction));
+
+        match position {
+            Some(Position::After) => {
+                info!("Stopping before line {}", line_number);
+                break;
+            }
+            Some(Position::Skipped) => continue,
+            _ => {}
         }
 
-        Self {
-            parallelism,
-            senders,
-            tasks,
-            engine,
+        engine.add_transaction(transaction)?;
+
+        if position == Some(Position::At) {
+            info!("Stopping after line {}", line_number);
+            break;
         }
     }
 
-    pub async fn add_transaction(&self, transaction: Transaction) -> Result<()> {
-        let associated_task = (transaction.client as usize) % self.parallelism;
+    Ok(engine)
+}
 
-        let sender = self
-            .senders
-            .get(&associated_task)
-            .expect("Must have created associated task");
+/// Parses on the parser threads, and applies on the task pool's workers
+async fn process_async(
+    args: &Args,
+    input: Input,
+    engine: TransactionEngine,
+    metrics: Option<Arc<Metrics>>,
+) -> Result<(TransactionEngine, Vec<WorkerFailure>)> {
+    let mut parser = ParallelParser::new(input, args.parsers, CHUNK_SIZE, metrics.clone())?;
+
+    let mut task_pool = TaskPool::new(engine, args.queue_depth, args.workers)
+        .with_batching(
+            args.batch_size,
+            Duration::from_millis(args.flush_interval_ms),
+        )
+        .with_sharding(args.sharding)
+        .with_rebalance_every(args.rebalance_every)
+        .with_on_failure(args.on_failure);
+    if let Some(metrics) = &metrics {
+        task_pool = task_pool.with_metrics(metrics);
+    }
+    if let Some(stats_interval_secs) = args.stats_interval_secs {
+        task_pool = task_pool.with_stats_interval(Duration::from_secs(stats_interval_secs));
+    }
 
-        sender.send_async(transaction).await?;
+    let mut cutoff = args.as_of.map(Cutoff::new);
 
-        Ok(())
-    }
+    loop {
+        // Don't hold on to a part-filled batch while waiting on slow input
+        let line = match tokio::time::timeout(task_pool.flush_interval(), parser.next()).await {
+            Ok(line) => line?,
+            Err(_) => {
+                task_pool.flush().await?;
+                continue;
+            }
+        };
 
-    pub async fn wait(mut self) -> Result<TransactionEngine> {
-        self.senders.clear();
+        let (line_number, transaction) = match line {
+            Some(line) => line,
+            None => break,
+        };
 
-        for task in self.tasks.drain(..) {
-            task.await??;
+        let position = cutoff
+            .as_mut()
+            .map(|cutoff| cutoff.position(line_number, &transaction));
+
+        match position {
+            Some(Position::After) => {
+                info!("Stopping before line {}", line_number);
+                break;
+            }
+            Some(Position::Skipped) => continue,
+            _ => {}
         }
 
-        Arc::try_unwrap(self.engine)
-            .map_err(|_| anyhow!("Did not have only reference to the engine!"))
+        task_pool.add_transaction(transaction).await?;
+
+        if position == Some(Position::At) {
+            info!("Stopping after line {}", line_number);
+            break;
+        }
+    }
+
+    match args.on_failure {
+        OnFailure::Stop => Ok((task_pool.wait().await?, Vec::new())),
+        OnFailure::Continue => task_pool.wait_partial().await,
     }
 }
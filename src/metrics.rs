@@ -0,0 +1,191 @@
+//! Prometheus metrics, for watching a run as it goes
+//!
+//! Metrics are only collected when a [`Metrics`] is given to the engine, task pool and parser.
+//! They can be served in Prometheus' text format over HTTP while running, and written to a
+//! file once done.
+
+use crate::prelude::*;
+use crate::transaction::{Currency, Kind};
+use crate::transaction_engine::Rejection;
+use prometheus::{
+    Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+
+    /// Transactions the engine was given, by [`Kind`], whether or not they were applied
+    transactions: Vec<IntCounter>,
+
+    /// Transactions the engine ignored, by [`Rejection`]
+    rejections: Vec<IntCounter>,
+
+    locked_accounts: IntGauge,
+    held: GaugeVec,
+    queue_depth: IntGaugeVec,
+    parse_errors: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("payment_engine".to_string()), None)?;
+
+        let transactions = IntCounterVec::new(
+            Opts::new(
+                "transactions_total",
+                "Transactions processed, whether or not they were applied",
+            ),
+            &["kind"],
+        )?;
+        let rejections = IntCounterVec::new(
+            Opts::new("rejections_total", "Transactions ignored, by why"),
+            &["reason"],
+        )?;
+        let locked_accounts = IntGauge::new("locked_accounts", "Accounts locked by a chargeback")?;
+        let held = GaugeVec::new(
+            Opts::new("held", "Funds held by open disputes, across every client"),
+            &["currency"],
+        )?;
+        let queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "worker_queue_depth",
+                "Batches waiting in each engine worker's queue",
+            ),
+            &["worker"],
+        )?;
+        let parse_errors = IntCounter::new(
+            "parse_errors_total",
+            "Input lines that were skipped or failed to parse",
+        )?;
+
+        registry.register(Box::new(transactions.clone()))?;
+        registry.register(Box::new(rejections.clone()))?;
+        registry.register(Box::new(locked_accounts.clone()))?;
+        registry.register(Box::new(held.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(parse_errors.clone()))?;
+
+        // Every label up front, so each is reported from the start, even while zero. Looking
+        // them up again for every transaction would also take a lock
+        Ok(Self {
+            registry,
+            transactions: Kind::ALL
+                .iter()
+                .map(|kind| transactions.with_label_values(&[&kind.to_string()]))
+                .collect(),
+            rejections: Rejection::ALL
+                .iter()
+                .map(|rejection| rejections.with_label_values(&[rejection.as_str()]))
+                .collect(),
+            locked_accounts,
+            held,
+            queue_depth,
+            parse_errors,
+        })
+    }
+
+    pub fn transaction(&self, kind: Kind) {
+        self.transactions[kind as usize].inc();
+    }
+
+    pub fn rejection(&self, rejection: Rejection) {
+        self.rejections[rejection as usize].inc();
+    }
+
+    pub fn locked_account(&self) {
+        self.locked_accounts.inc();
+    }
+
+    /// Adds to the funds held in a currency, or takes from them if `change` is negative
+    pub fn held(&self, currency: &Currency, change: Decimal) {
+        self.held
+            .with_label_values(&[&currency.to_string()])
+            .add(change.to_f64().unwrap_or_default());
+    }
+
+    /// The gauge for how many batches are waiting in a worker's queue
+    pub fn queue_depth(&self, worker: usize) -> IntGauge {
+        self.queue_depth.with_label_values(&[&worker.to_string()])
+    }
+
+    pub fn parse_error(&self) {
+        self.parse_errors.inc();
+    }
+
+    /// Every metric, in Prometheus' text format
+    pub fn encode(&self) -> Result<String> {
+        let mut text = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut text)?;
+
+        Ok(String::from_utf8(text)?)
+    }
+
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.encode()?)
+            .with_context(|| format!("Could not write metrics to {:?}", path))
+    }
+
+    /// Serves the metrics over HTTP on its own thread, at any path, until the process exits
+    pub fn serve(self: &Arc<Self>, address: SocketAddr) -> Result<()> {
+        let server = tiny_http::Server::http(address)
+            .map_err(|error| anyhow!("Could not serve metrics on {}: {}", address, error))?;
+        info!("Serving metrics on http://{}/metrics", address);
+
+        let metrics = Arc::clone(self);
+        let content_type =
+            tiny_http::Header::from_bytes("Content-Type", TextEncoder::new().format_type())
+                .map_err(|_| anyhow!("Invalid content type header"))?;
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = match metrics.encode() {
+                    Ok(text) => {
+                        tiny_http::Response::from_string(text).with_header(content_type.clone())
+                    }
+                    Err(error) => tiny_http::Response::from_string(format!("{:#}", error))
+                        .with_status_code(500),
+                };
+
+                if let Err(error) = request.respond(response) {
+                    warn!("Could not send metrics: {}", error);
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[test]
+fn encodes_every_kind_and_reason() -> Result<()> {
+    let metrics = Metrics::new()?;
+    metrics.transaction(Kind::Deposit);
+    metrics.transaction(Kind::Deposit);
+    metrics.rejection(Rejection::InsufficientFunds);
+    metrics.held(&Currency::default(), Decimal::new(1050, 2));
+    metrics.queue_depth(3).set(7);
+
+    let text = metrics.encode()?;
+    for line in [
+        "payment_engine_transactions_total{kind=\"deposit\"} 2",
+        "payment_engine_transactions_total{kind=\"transfer\"} 0",
+        "payment_engine_rejections_total{reason=\"insufficient_funds\"} 1",
+        "payment_engine_rejections_total{reason=\"no_dispute\"} 0",
+        "payment_engine_held{currency=\"USD\"} 10.5",
+        "payment_engine_worker_queue_depth{worker=\"3\"} 7",
+        "payment_engine_locked_accounts 0",
+        "payment_engine_parse_errors_total 0",
+    ] {
+        assert!(text.lines().any(|actual| actual == line), "{}", text);
+    }
+
+    Ok(())
+}
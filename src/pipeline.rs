@@ -0,0 +1,245 @@
+//! Parses input CSV on several threads
+//!
+//! A reader thread splits the input into chunks of whole lines, and parser threads each take
+//! the next chunk and parse it with their own [`CsvParser`]. Each chunk's result has its own
+//! channel, which is queued in the order the chunks were read, so transactions come out in
+//! the same order they went in however the parsers are scheduled. That keeps every client's
+//! transactions in order for the task pool.
+//!
+//! Both queues are bounded, so only a few chunks are in memory at once. Chunks of a memory
+//! mapped input are only ranges of the map, so aren't copied at all.
+
+use crate::csv::{CsvParser, LikelyValidLine};
+use crate::input::{self, Chunk, Input, Lines};
+use crate::metrics::Metrics;
+use crate::prelude::*;
+use crate::transaction::Transaction;
+use flume::{bounded, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+/// How many bytes of input each chunk holds, give or take the rest of its last line
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// A parsed transaction, and the line of the input it came from (the header is line 1)
+pub type ParsedLine = (usize, Transaction);
+
+/// The lines of a single chunk, stopping at the first that failed to parse
+type ParsedChunk = Vec<Result<ParsedLine>>;
+
+/// A chunk of whole lines, the line number of the first, and where to send the result
+type Work = (Chunk, usize, Sender<ParsedChunk>);
+
+pub struct ParallelParser {
+    chunks: Receiver<Receiver<ParsedChunk>>,
+
+    /// The chunk being waited on. Kept here so that waiting can be cancelled without losing it
+    pending: Option<Receiver<ParsedChunk>>,
+    lines: std::vec::IntoIter<Result<ParsedLine>>,
+}
+
+impl ParallelParser {
+    /// Reads the header, then starts reading and parsing the rest of the input on `parsers`
+    /// threads. Lines that are skipped or fail to parse are counted in `metrics`, if given
+    pub fn new(
+        input: Input,
+        parsers: usize,
+        chunk_size: usize,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Result<Self> {
+        let parsers = std::cmp::max(1, parsers);
+
+        let (header_line, lines) = input.lines()?;
+        // Fail now on bad headers, rather than in every parser
+        CsvParser::new(header_line.clone())?;
+
+        let (work_sender, work) = bounded::<Work>(parsers);
+        let (chunk_sender, chunks) = bounded(parsers * 2);
+
+        for _ in 0..parsers {
+            let work = work.clone();
+            let header_line = header_line.clone();
+            let metrics = metrics.clone();
+            thread::spawn(move || parse_chunks(header_line, work, metrics));
+        }
+
+        thread::spawn(move || read_chunks(lines, chunk_size, work_sender, chunk_sender));
+
+        Ok(Self {
+            chunks,
+            pending: None,
+            lines: Vec::new().into_iter(),
+        })
+    }
+
+    /// The next transaction in the input, or `None` once it's all been read
+    ///
+    /// Cancelling this, like with a timeout, loses nothing.
+    pub async fn next(&mut self) -> Result<Option<ParsedLine>> {
+        loop {
+            if let Some(line) = self.lines.next() {
+                return line.map(Some);
+            }
+
+            let pending = match self.pending.take() {
+                Some(pending) => pending,
+                None => match self.chunks.recv_async().await {
+                    Ok(pending) => pending,
+                    // The reader has finished
+                    Err(_) => return Ok(None),
+                },
+            };
+            let result = self.pending.insert(pending).recv_async().await;
+            self.pending = None;
+
+            self.lines = result
+                .map_err(|_| anyhow!("A parser thread stopped before finishing its chunk"))?
+                .into_iter();
+        }
+    }
+}
+
+fn read_chunks(
+    mut lines: Lines,
+    chunk_size: usize,
+    work: Sender<Work>,
+    chunks: Sender<Receiver<ParsedChunk>>,
+) {
+    loop {
+        let chunk = match lines.next_chunk(chunk_size) {
+            Ok(Some(chunk)) => Ok(chunk),
+            Ok(None) => return,
+            Err(error) => Err(error),
+        };
+
+        let (result_sender, result) = bounded(1);
+        // If the chunks are no longer wanted, nor is anything else
+        if chunks.send(result).is_err() {
+            return;
+        }
+
+        let (line_number, chunk) = match chunk {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                let _ = result_sender.send(vec![Err(error)]);
+                return;
+            }
+        };
+
+        if work.send((chunk, line_number, result_sender)).is_err() {
+            return;
+        }
+    }
+}
+
+fn parse_chunks(
+    header_line: LikelyValidLine,
+    work: Receiver<Work>,
+    metrics: Option<Arc<Metrics>>,
+) -> Result<()> {
+    let mut csv_parser = CsvParser::new(header_line)?;
+
+    for (chunk, first_line_number, result) in work {
+        let mut lines = Vec::new();
+
+        for (line_number, line) in (first_line_number..).zip(input::lines(&chunk)) {
+            match CsvParser::valid_bytes(line) {
+                Some(valid_line) => match csv_parser.bytes_to_transaction(valid_line) {
+                    Ok(transaction) => lines.push(Ok((line_number, transaction))),
+                    Err(error) => {
+                        if let Some(metrics) = &metrics {
+                            metrics.parse_error();
+                        }
+                        lines.push(Err(error.context(format!("On line {}", line_number))));
+                        break;
+                    }
+                },
+                None => {
+                    if let Some(metrics) = &metrics {
+                        metrics.parse_error();
+                    }
+                    warn!("Get an invalid line, skipping");
+                }
+            }
+        }
+
+        // Nobody is waiting if the input stopped being read early
+        let _ = result.send(lines);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn matches_serial_parsing() -> Result<()> {
+    use crate::generate::{Generator, GeneratorConfig};
+
+    let mut input = Vec::new();
+    Generator::new(GeneratorConfig {
+        transactions: 2_000,
+        mix: "deposit=5,withdrawal=3,dispute=2,resolve=1,chargeback=1,transfer=1".parse()?,
+        ..GeneratorConfig::default()
+    })?
+    .write_csv(&mut input)?;
+    // An invalid line part way through still counts towards the line numbers
+    input.extend_from_slice(b"\ndeposit,1,1000000,1.0,\n");
+    let input = String::from_utf8(input)?;
+
+    let mut lines = input.lines();
+    let mut csv_parser =
+        CsvParser::new(CsvParser::valid_line(lines.next().unwrap().into()).unwrap())?;
+    let mut expected = Vec::new();
+    for (line_number, line) in (2..).zip(lines) {
+        if let Some(valid_line) = CsvParser::valid_line(line.to_string()) {
+            expected.push((line_number, csv_parser.line_to_transaction(valid_line)?));
+        }
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    for (parsers, chunk_size) in [(1, 1), (3, 7), (4, 1_000), (2, CHUNK_SIZE)] {
+        let mut parallel_parser = ParallelParser::new(
+            Input::Stream(Box::new(std::io::Cursor::new(input.clone()))),
+            parsers,
+            chunk_size,
+            None,
+        )?;
+
+        let mut actual = Vec::new();
+        while let Some(line) = runtime.block_on(parallel_parser.next())? {
+            actual.push(line);
+        }
+
+        assert_eq!(actual.len(), expected.len());
+        for ((line_number, transaction), (expected_line_number, expected_transaction)) in
+            actual.iter().zip(&expected)
+        {
+            assert_eq!(line_number, expected_line_number);
+            assert_eq!(transaction.client, expected_transaction.client);
+            assert_eq!(
+                transaction.transaction_id,
+                expected_transaction.transaction_id
+            );
+            assert_eq!(transaction.kind, expected_transaction.kind);
+            assert_eq!(transaction.amount, expected_transaction.amount);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn stops_at_the_first_error() -> Result<()> {
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,lots\ndeposit,1,3,1.0\n";
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let mut parallel_parser =
+        ParallelParser::new(Input::Stream(Box::new(input.as_bytes())), 2, 1, None)?;
+
+    assert_eq!(
+        runtime.block_on(parallel_parser.next())?.map(|line| line.0),
+        Some(2)
+    );
+    let error = runtime.block_on(parallel_parser.next()).unwrap_err();
+    assert!(format!("{:#}", error).contains("line 3"));
+
+    Ok(())
+}
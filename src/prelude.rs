@@ -1,2 +1,2 @@
 pub use anyhow::{anyhow, Context, Result};
-pub use log::{error, info, warn};
+pub use tracing::{debug_span, error, info, warn, warn_span};
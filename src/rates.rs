@@ -0,0 +1,135 @@
+//! Exchange rates
+//!
+//! Rates are loaded once at startup from a CSV in the following form:
+//!
+//! ```text
+//! from, to,  rate
+//! EUR,  USD, 1.08
+//! ```
+//!
+//! Each row means one unit of `from` buys `rate` units of `to`. If only the
+//! opposite direction is listed, its inverse is used.
+
+use crate::prelude::*;
+use crate::transaction::Currency;
+use csv::{ReaderBuilder, Trim};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Fails unless `rate` is positive, as a rate of zero or less would destroy or create funds
+pub fn check_rate(from: &Currency, to: &Currency, rate: Decimal) -> Result<()> {
+    if rate <= Decimal::ZERO {
+        return Err(anyhow!(
+            "Exchange rates must be positive, got {} for {} to {}",
+            rate,
+            from,
+            to
+        ));
+    }
+
+    Ok(())
+}
+
+/// The number of decimal places an exchanged amount is kept to
+pub const EXCHANGE_DECIMAL_PLACES: u32 = 4;
+
+#[derive(Default, Debug, Clone)]
+pub struct RateTable {
+    rates: HashMap<(Currency, Currency), Decimal>,
+}
+
+#[derive(Deserialize)]
+struct RateRow {
+    from: Currency,
+    to: Currency,
+    #[serde(with = "rust_decimal::serde::str")]
+    rate: Decimal,
+}
+
+impl RateTable {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Could not open exchange rates file {:?}", path))?;
+        Self::from_reader(file)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut table = Self::default();
+
+        for row in ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(reader)
+            .into_deserialize()
+        {
+            let row: RateRow = row?;
+            table.insert(row.from, row.to, row.rate)?;
+        }
+
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, from: Currency, to: Currency, rate: Decimal) -> Result<()> {
+        check_rate(&from, &to, rate)?;
+
+        self.rates.insert((from, to), rate);
+        Ok(())
+    }
+
+    /// How many units of `to` one unit of `from` buys, if known
+    pub fn rate(&self, from: &Currency, to: &Currency) -> Option<Decimal> {
+        if from == to {
+            return Some(Decimal::ONE);
+        }
+
+        self.rates
+            .get(&(from.clone(), to.clone()))
+            .copied()
+            .or_else(|| {
+                self.rates
+                    .get(&(to.clone(), from.clone()))
+                    .and_then(|inverse| Decimal::ONE.checked_div(*inverse))
+            })
+    }
+}
+
+/// Converts an amount at the given rate
+///
+/// The result is truncated (rounded toward zero) to [`EXCHANGE_DECIMAL_PLACES`], so an exchange
+/// never credits more than the rate allows.
+pub fn convert(amount: Decimal, rate: Decimal) -> Result<Decimal> {
+    amount
+        .checked_mul(rate)
+        .map(|converted| {
+            converted.round_dp_with_strategy(EXCHANGE_DECIMAL_PLACES, RoundingStrategy::ToZero)
+        })
+        .ok_or_else(|| anyhow!("Overflow converting {} at rate {}", amount, rate))
+}
+
+#[test]
+fn rate_lookup_and_rounding() -> Result<()> {
+    let table = RateTable::from_reader(
+        r#"from, to,  rate
+EUR,  USD, 1.5
+"#
+        .as_bytes(),
+    )?;
+
+    let eur = Currency::from("EUR");
+    let usd = Currency::from("USD");
+
+    assert_eq!(
+        table.rate(&eur, &usd),
+        Some(Decimal::from_str_exact("1.5")?)
+    );
+    assert_eq!(
+        convert(Decimal::from(2), table.rate(&usd, &eur).unwrap())?,
+        Decimal::from_str_exact("1.3333")?
+    );
+    assert_eq!(table.rate(&eur, &Currency::from("GBP")), None);
+
+    Ok(())
+}
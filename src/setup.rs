@@ -3,20 +3,46 @@
 //! This module includes all functions required to
 //! set up things like logging, metrics, etc.
 
-use crate::prelude::*;
-use simplelog::{ColorChoice, Config, LevelFilter, TermLogger, TerminalMode};
+use crate::cli::{Args, LogFormat};
+use payment_engine::prelude::*;
+use std::fs::OpenOptions;
+use std::io::stderr;
+use std::sync::Arc;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
 
-pub fn setup() -> Result<()> {
-    logger()?;
+pub fn setup(args: &Args) -> Result<()> {
+    logger(args)?;
     Ok(())
 }
 
-fn logger() -> Result<()> {
-    TermLogger::init(
-        LevelFilter::Info,
-        Config::default(),
-        TerminalMode::Stderr,
-        ColorChoice::Auto,
-    )?;
-    Ok(())
+fn logger(args: &Args) -> Result<()> {
+    let filter = match &args.log_level {
+        Some(level) => {
+            EnvFilter::try_new(level).with_context(|| format!("Invalid log level {:?}", level))?
+        }
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+
+    let (writer, ansi) = match &args.log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Could not open log file {:?}", path))?;
+            (BoxMakeWriter::new(Arc::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(stderr), atty::is(atty::Stream::Stderr)),
+    };
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+
+    match args.log_format {
+        LogFormat::Text => subscriber.with_ansi(ansi).try_init(),
+        LogFormat::Json => subscriber.json().try_init(),
+    }
+    .map_err(|error| anyhow!("Could not set up logging: {}", error))
 }
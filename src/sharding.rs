@@ -0,0 +1,251 @@
+//! Which worker each client's transactions go to
+//!
+//! Every transaction for a client goes to the same worker until the client is moved, which
+//! keeps them in order. A client is only moved by queueing its hand over behind everything
+//! already sent to its old worker, and in front of everything sent to its new one. See
+//! [`TaskPool`](crate::task_pool::TaskPool).
+
+use crate::transaction::UserId;
+use std::collections::HashMap;
+
+/// How many points each worker has on the consistent hashing ring. More points spread
+/// clients more evenly
+const POINTS_PER_WORKER: u64 = 64;
+
+/// How many jobs are routed between looking for clients to move
+pub const REBALANCE_EVERY: u64 = 10_000;
+
+/// How much busier than the average the busiest worker can be before clients are moved off it
+const IMBALANCE: f64 = 1.25;
+
+/// How clients are spread across workers
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharding {
+    /// The client ID modulo the number of workers
+    #[default]
+    Modulo,
+
+    /// A consistent hash of the client ID. Clients whose IDs share a stride, which modulo
+    /// puts on the same worker, are spread out
+    Hash,
+
+    /// Starts out like modulo, then moves quieter clients off the busiest worker, so it can
+    /// keep up with its busiest client
+    Rebalance,
+}
+
+/// A client to move, and the workers it's moving between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub client: UserId,
+    pub from: usize,
+    pub to: usize,
+}
+
+pub struct Router {
+    sharding: Sharding,
+    workers: usize,
+
+    /// Points on the consistent hashing ring, and the worker each belongs to, in order
+    ring: Vec<(u64, usize)>,
+
+    /// Clients that have been moved off the worker they started on
+    moved: HashMap<UserId, usize>,
+
+    /// Jobs routed to each worker and each client since the last rebalance. Only counted
+    /// when rebalancing
+    worker_load: Vec<u64>,
+    client_load: HashMap<UserId, u64>,
+    rebalance_every: u64,
+}
+
+impl Router {
+    pub fn new(sharding: Sharding, workers: usize) -> Self {
+        let workers = std::cmp::max(1, workers);
+
+        let mut ring = Vec::new();
+        if sharding == Sharding::Hash {
+            for worker in 0..workers {
+                for point in 0..POINTS_PER_WORKER {
+                    ring.push((mix(((worker as u64) << 32) | point), worker));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        Self {
+            sharding,
+            workers,
+            ring,
+            moved: HashMap::new(),
+            worker_load: vec![0; workers],
+            client_load: HashMap::new(),
+            rebalance_every: REBALANCE_EVERY,
+        }
+    }
+
+    /// Looks for clients to move every `jobs` jobs, rather than every [`REBALANCE_EVERY`]
+    pub fn with_rebalance_every(mut self, jobs: u64) -> Self {
+        self.rebalance_every = std::cmp::max(1, jobs);
+        self
+    }
+
+    pub fn rebalance_every(&self) -> u64 {
+        self.rebalance_every
+    }
+
+    /// The worker a client's transactions currently go to
+    pub fn worker(&self, client: UserId) -> usize {
+        match self.moved.get(&client) {
+            Some(&worker) => worker,
+            None => self.first_worker(client),
+        }
+    }
+
+    /// The worker a client starts out on
+    fn first_worker(&self, client: UserId) -> usize {
+        match self.sharding {
+            Sharding::Modulo | Sharding::Rebalance => usize::from(client) % self.workers,
+            Sharding::Hash => {
+                let hash = mix(u64::from(client));
+                let point = self.ring.partition_point(|&(point, _)| point < hash);
+                // Past the last point wraps around to the first
+                self.ring[point % self.ring.len()].1
+            }
+        }
+    }
+
+    /// Counts a job for a client towards its worker's load
+    pub fn record(&mut self, client: UserId) {
+        if self.sharding != Sharding::Rebalance {
+            return;
+        }
+
+        let worker = self.worker(client);
+        self.worker_load[worker] += 1;
+        *self.client_load.entry(client).or_default() += 1;
+    }
+
+    /// Whether enough jobs have been routed since the last rebalance to look for clients to move
+    pub fn rebalance_due(&self) -> bool {
+        self.sharding == Sharding::Rebalance
+            && self.worker_load.iter().sum::<u64>() >= self.rebalance_every
+    }
+
+    /// Picks clients to move off the busiest worker, and starts counting load afresh
+    ///
+    /// The busiest worker's busiest client stays put, as moving it only moves the problem.
+    /// Its quieter clients move to the least busy worker, until about half the difference
+    /// between the two has moved.
+    pub fn rebalance(&mut self) -> Vec<Move> {
+        let worker_load = std::mem::replace(&mut self.worker_load, vec![0; self.workers]);
+        let client_load = std::mem::take(&mut self.client_load);
+
+        let total: u64 = worker_load.iter().sum();
+        let average = total as f64 / self.workers as f64;
+        let busiest = (0..self.workers).max_by_key(|&worker| worker_load[worker]);
+        let idlest = (0..self.workers).min_by_key(|&worker| worker_load[worker]);
+
+        let (busiest, idlest) = match (busiest, idlest) {
+            (Some(busiest), Some(idlest)) if worker_load[busiest] as f64 > average * IMBALANCE => {
+                (busiest, idlest)
+            }
+            _ => return Vec::new(),
+        };
+
+        let mut clients: Vec<(UserId, u64)> = client_load
+            .into_iter()
+            .filter(|&(client, _)| self.worker(client) == busiest)
+            .collect();
+        // Ties broken by client, so the same input always moves the same clients
+        clients.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let mut to_move = (worker_load[busiest] - worker_load[idlest]) / 2;
+        let mut moves = Vec::new();
+
+        for (client, load) in clients.into_iter().skip(1) {
+            if load > to_move {
+                continue;
+            }
+
+            to_move -= load;
+            if self.first_worker(client) == idlest {
+                self.moved.remove(&client);
+            } else {
+                self.moved.insert(client, idlest);
+            }
+
+            moves.push(Move {
+                client,
+                from: busiest,
+                to: idlest,
+            });
+        }
+
+        moves
+    }
+}
+
+/// Scrambles the bits of a number, so that nearby numbers hash far apart (SplitMix64's finaliser)
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[test]
+fn hashing_spreads_strided_clients() {
+    let modulo = Router::new(Sharding::Modulo, 4);
+    let hash = Router::new(Sharding::Hash, 4);
+
+    // Every client that's a multiple of 4 is on the same worker with modulo
+    let mut modulo_workers = [0; 4];
+    let mut hash_workers = [0; 4];
+    for client in (0..4_000).step_by(4) {
+        modulo_workers[modulo.worker(client)] += 1;
+        hash_workers[hash.worker(client)] += 1;
+    }
+
+    assert_eq!(modulo_workers, [1_000, 0, 0, 0]);
+    assert!(
+        hash_workers.iter().all(|&clients| clients > 150),
+        "{:?}",
+        hash_workers
+    );
+}
+
+#[test]
+fn rebalancing_moves_quieter_clients_off_the_busiest_worker() {
+    let mut router = Router::new(Sharding::Rebalance, 2).with_rebalance_every(100);
+
+    // Worker 0 has a hot client, 0, and two quieter ones. Worker 1 only has client 1
+    for (client, jobs) in [(0, 50), (2, 30), (4, 20), (1, 40)] {
+        for _ in 0..jobs {
+            router.record(client);
+        }
+    }
+    assert!(router.rebalance_due());
+
+    // 100 against 40, so up to 30 can move, which is all of client 2
+    assert_eq!(
+        router.rebalance(),
+        vec![Move {
+            client: 2,
+            from: 0,
+            to: 1
+        }]
+    );
+    assert_eq!(
+        (router.worker(0), router.worker(2), router.worker(4)),
+        (0, 1, 0)
+    );
+    assert!(!router.rebalance_due());
+
+    // Even enough already, so nothing moves
+    for client in [0, 1, 2, 4] {
+        for _ in 0..25 {
+            router.record(client);
+        }
+    }
+    assert!(router.rebalance().is_empty());
+}
@@ -0,0 +1,1041 @@
+//! Runs the transaction engine across worker threads
+//!
+//! Transactions are sharded across workers by client, so that every transaction for a
+//! client is processed in the order it was added. Which worker a client is on depends on the
+//! [`Sharding`] strategy.
+//!
+//! Transfers touch two clients, which may live on different workers. These are split in
+//! two: the source client's worker debits the source, then hands the result to the
+//! destination client's worker, which waits for it before crediting the destination.
+//! Both halves are queued in the order the transfer was added, so the destination sees
+//! the credit exactly where a single worker would have applied it.
+//!
+//! Clients are moved between workers the same way. Their old worker evicts them once it has
+//! applied everything queued for them, and hands their state to the new worker, which waits
+//! for it before applying anything else for them.
+//!
+//! This can't deadlock: a worker only ever waits on a transfer or move added before the one
+//! it's working on, and the earliest unfinished one never has anything to wait on.
+//!
+//! Jobs are sent to workers in batches, which fill up in the order jobs were added. A batch
+//! is sent once it's full, or once its oldest job has waited for the flush interval. A
+//! batch holding a transfer's debit is sent straight away, so a destination worker never
+//! waits on a debit that's still sitting in a batch.
+//!
+//! Each worker owns a [shard](TransactionEngine::shard) of the engine holding only its own
+//! clients, so workers never share state or contend on locks. The shards are merged back
+//! into one engine once every transaction has been applied.
+//!
+//! A worker that fails to apply a transaction reports it straight away, then skips the rest
+//! of its jobs, only answering transfers so other workers aren't left waiting. Its shard is
+//! left out when the others are merged. What happens to the rest depends on [`OnFailure`].
+//! Clients stop being moved once a worker has failed. A client already on its way off a
+//! failed worker is lost: the worker it was moving to reports it, then skips the client's
+//! jobs but keeps applying everyone else's.
+//!
+//! [`TaskPool::stats`] shows how full each worker's queue is, how long sending to it has
+//! blocked, and how evenly jobs are spread across workers, which helps with picking a queue
+//! depth and number of workers.
+
+use crate::metrics::Metrics;
+use crate::prelude::*;
+use crate::sharding::{Move, Router, Sharding};
+use crate::transaction::{Kind, Transaction, TransactionId, UserId};
+use crate::transaction_engine::{PendingCredit, TransactionEngine, UserState};
+use flume::{bounded, unbounded, Receiver, Sender};
+use prometheus::IntGauge;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// A unit of work for a single worker
+enum Job {
+    /// A transaction that only touches one worker's clients
+    Apply(Transaction),
+
+    /// The source half of a transfer between workers. The outcome is sent to the destination's worker
+    Debit(Transaction, Sender<Option<PendingCredit>>),
+
+    /// The destination half of a transfer between workers, with its destination client and
+    /// transaction ID. Waits for the outcome from the source's worker
+    Credit(UserId, TransactionId, Receiver<Option<PendingCredit>>),
+
+    /// Takes a client out of this worker's shard, and sends it to the worker taking it over
+    Evict(UserId, Sender<Option<UserState>>),
+
+    /// Takes over a client from another worker, waiting for it to be evicted
+    Adopt(UserId, Receiver<Option<UserState>>),
+}
+
+impl Job {
+    /// The client on this job's worker, and the transaction the job is for, if any
+    fn client_and_transaction(&self) -> (UserId, Option<TransactionId>) {
+        match self {
+            Self::Apply(transaction) | Self::Debit(transaction, _) => {
+                (transaction.client, Some(transaction.transaction_id))
+            }
+            Self::Credit(to_client, transaction_id, _) => (*to_client, Some(*transaction_id)),
+            Self::Evict(client, _) | Self::Adopt(client, _) => (*client, None),
+        }
+    }
+
+    /// Whether this job touches any of `clients` on this job's worker
+    fn touches(&self, clients: &HashSet<UserId>) -> bool {
+        let (client, _) = self.client_and_transaction();
+
+        // Both sides of a transfer between clients on the same worker
+        let to_client = match self {
+            Self::Apply(transaction) => transaction.to_client,
+            _ => None,
+        };
+
+        clients.contains(&client) || matches!(to_client, Some(to) if clients.contains(&to))
+    }
+
+    fn apply(self, shard: &mut TransactionEngine) -> Result<()> {
+        match self {
+            Self::Apply(transaction) => shard.add_transaction(transaction)?,
+            Self::Debit(transaction, outcome) => {
+                let credit = shard.apply_to_client(transaction)?;
+                outcome
+                    .send(credit)
+                    .map_err(|_| anyhow!("Worker for the transfer destination has stopped"))?;
+            }
+            Self::Credit(_, _, outcome) => {
+                let credit = outcome.recv().map_err(|_| {
+                    anyhow!("Worker for the transfer source stopped before debiting")
+                })?;
+
+                if let Some(credit) = credit {
+                    shard.credit(credit)?;
+                }
+            }
+            Self::Evict(client, user_state) => {
+                user_state
+                    .send(shard.evict(client))
+                    .map_err(|_| anyhow!("Worker taking over client {} has stopped", client))?;
+            }
+            Self::Adopt(client, user_state) => {
+                let user_state = user_state.recv().map_err(|_| ClientLost)?;
+
+                if let Some(user_state) = user_state {
+                    shard.adopt(client, user_state)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers the other half of a transfer without applying anything
+    fn skip(self) {
+        match self {
+            Self::Apply(_) => {}
+            // Nothing was debited, so there's nothing to credit
+            Self::Debit(_, outcome) => {
+                let _ = outcome.send(None);
+            }
+            Self::Credit(to_client, transaction_id, outcome) => {
+                if let Ok(Some(credit)) = outcome.recv() {
+                    warn!(
+                        "Transfer {} of {} {} to client {} was debited, but its destination's worker has failed",
+                        transaction_id, credit.amount, credit.currency, to_client
+                    );
+                }
+            }
+            // Dropping the sender tells the worker taking over the client that it's lost
+            Self::Evict(..) => {}
+            Self::Adopt(client, user_state) => {
+                if let Ok(Some(_)) = user_state.recv() {
+                    warn!(
+                        "Client {} was handed over, but its new worker has failed",
+                        client
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// What the task pool does once a worker has failed
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnFailure {
+    /// Stop taking transactions, and fail
+    #[default]
+    Stop,
+
+    /// Keep applying the other workers' transactions, and leave out the failed workers' clients
+    Continue,
+}
+
+/// Why a worker took over a client without its state: the worker handing it over failed first
+#[derive(Debug)]
+struct ClientLost;
+
+impl fmt::Display for ClientLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the worker handing it over failed first")
+    }
+}
+
+impl std::error::Error for ClientLost {}
+
+/// A worker that failed, and the transaction it failed on. There's no transaction if it
+/// failed moving a client
+#[derive(Debug)]
+pub struct WorkerFailure {
+    pub worker: usize,
+    pub client: UserId,
+    pub transaction_id: Option<TransactionId>,
+    pub error: anyhow::Error,
+
+    /// Whether the worker only lost `client`, which it was taking over, and kept applying
+    /// its other clients' jobs
+    pub client_lost: bool,
+}
+
+impl fmt::Display for WorkerFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.client_lost {
+            return write!(
+                f,
+                "worker {} lost client {}, as {:#}",
+                self.worker, self.client, self.error
+            );
+        }
+
+        match self.transaction_id {
+            Some(transaction_id) => write!(
+                f,
+                "worker {} failed on transaction {} for client {}: {:#}",
+                self.worker, transaction_id, self.client, self.error
+            ),
+            None => write!(
+                f,
+                "worker {} failed moving client {}: {:#}",
+                self.worker, self.client, self.error
+            ),
+        }
+    }
+}
+
+/// A snapshot of how busy a worker has been
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Batches waiting in its queue
+    pub queued: usize,
+
+    /// The most batches that have been waiting in its queue at once
+    pub peak_queued: usize,
+
+    /// Jobs it has finished, including those skipped after failing. Each half of a transfer
+    /// between workers is a job
+    pub processed: u64,
+
+    /// Time spent sending it batches, which is mostly waiting for room in its queue
+    pub blocked: Duration,
+}
+
+/// A snapshot of how busy every worker has been
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolStats {
+    /// How many batches each worker's queue holds
+    pub queue_depth: usize,
+    pub workers: Vec<WorkerStats>,
+}
+
+impl PoolStats {
+    /// How many times more jobs the busiest worker has processed than the average worker.
+    /// `1.0` is perfectly even
+    pub fn skew(&self) -> f64 {
+        let total: u64 = self.workers.iter().map(|worker| worker.processed).sum();
+        let busiest = self.workers.iter().map(|worker| worker.processed).max();
+
+        match busiest {
+            Some(busiest) if total > 0 => busiest as f64 * self.workers.len() as f64 / total as f64,
+            _ => 1.0,
+        }
+    }
+
+    fn log(&self) {
+        for (worker, stats) in self.workers.iter().enumerate() {
+            info!(
+                "Engine worker {}: {} jobs processed, {}/{} batches queued (peak {}), {:?} blocked sending",
+                worker,
+                stats.processed,
+                stats.queued,
+                self.queue_depth,
+                stats.peak_queued,
+                stats.blocked
+            );
+        }
+        info!(
+            "Busiest engine worker processed {:.2}x the average",
+            self.skew()
+        );
+    }
+}
+
+/// What the pool keeps track of for each worker's queue
+#[derive(Default)]
+struct QueueStats {
+    /// Counted by the worker itself
+    processed: Arc<AtomicU64>,
+    peak_queued: usize,
+    blocked: Duration,
+}
+
+/// Applies jobs until there are no more, returning the shard unless a job failed
+fn work(
+    worker: usize,
+    mut shard: TransactionEngine,
+    jobs: Receiver<Vec<Job>>,
+    failures: Sender<WorkerFailure>,
+    processed: Arc<AtomicU64>,
+) -> Option<TransactionEngine> {
+    let _span = warn_span!("worker", worker).entered();
+    let mut failed = false;
+    let mut skipped = 0;
+
+    // Clients whose state never arrived when taking them over
+    let mut lost = HashSet::new();
+
+    for batch in jobs {
+        let batch_size = batch.len() as u64;
+
+        for job in batch {
+            if failed || job.touches(&lost) {
+                job.skip();
+                skipped += 1;
+                continue;
+            }
+
+            let (client, transaction_id) = job.client_and_transaction();
+            if let Err(error) = job.apply(&mut shard) {
+                let client_lost = error.is::<ClientLost>();
+                let failure = WorkerFailure {
+                    worker,
+                    client,
+                    transaction_id,
+                    error,
+                    client_lost,
+                };
+
+                if client_lost {
+                    warn!("Engine {}", failure);
+                    lost.insert(client);
+                } else {
+                    error!("Engine {}", failure);
+                    failed = true;
+                }
+
+                // The pool only stops listening once every worker has finished
+                let _ = failures.send(failure);
+            }
+        }
+
+        // Once a batch, so the pool reading it costs the worker next to nothing
+        processed.fetch_add(batch_size, Ordering::Relaxed);
+    }
+
+    if failed {
+        warn!(
+            "Engine worker {} skipped {} jobs after failing",
+            worker, skipped
+        );
+        None
+    } else {
+        if !lost.is_empty() {
+            warn!(
+                "Engine worker {} skipped {} jobs for the {} clients it lost",
+                worker,
+                skipped,
+                lost.len()
+            );
+        }
+        Some(shard)
+    }
+}
+
+pub struct TaskPool {
+    parallelism: usize,
+    router: Router,
+    senders: HashMap<usize, Sender<Vec<Job>>>,
+    queue_depth: usize,
+    queue_stats: Vec<QueueStats>,
+
+    /// How often to log [`TaskPool::stats`] while taking transactions, if at all
+    stats_interval: Option<Duration>,
+    stats_logged: Instant,
+
+    /// Each worker's queue depth metric, set whenever it's sent a batch. Empty without metrics
+    queue_depth_gauges: Vec<IntGauge>,
+
+    /// Jobs waiting to be sent to each worker
+    batches: Vec<Vec<Job>>,
+    batch_size: usize,
+    flush_interval: Duration,
+
+    /// When the oldest job still waiting in each worker's batch was added
+    oldest: Vec<Option<Instant>>,
+
+    on_failure: OnFailure,
+    failures: Receiver<WorkerFailure>,
+
+    tasks: Vec<JoinHandle<Option<TransactionEngine>>>,
+    engine: TransactionEngine,
+}
+
+impl TaskPool {
+    /// Queues up to `queue_depth` batches for each worker. Each job is sent on its own until
+    /// [`TaskPool::with_batching`] is used
+    pub fn new(engine: TransactionEngine, queue_depth: usize, num_workers: isize) -> Self {
+        let system_parallelism = std::thread::available_parallelism()
+            .expect("Required parallel capable environment")
+            .get();
+
+        let parallelism = if num_workers < 0 {
+            // subtract from total available
+            std::cmp::max(1, (system_parallelism as isize) + num_workers) as usize
+        } else if num_workers == 0 {
+            // set to available
+            system_parallelism
+        } else {
+            // set to num
+            num_workers as usize
+        };
+
+        info!("Using {parallelism} engine worker threads");
+
+        let mut senders = HashMap::new();
+        let mut tasks = Vec::new();
+        let mut queue_stats = Vec::new();
+        let (failure_sender, failures) = unbounded();
+
+        for i in 0..(parallelism) {
+            let (sender, recv) = bounded(queue_depth);
+            let shard = engine.shard();
+            let failure_sender = failure_sender.clone();
+            let stats = QueueStats::default();
+            let processed = Arc::clone(&stats.processed);
+            tasks.push(tokio::task::spawn_blocking(move || {
+                work(i, shard, recv, failure_sender, processed)
+            }));
+            senders.insert(i, sender);
+            queue_stats.push(stats);
+        }
+
+        Self {
+            parallelism,
+            router: Router::new(Sharding::default(), parallelism),
+            senders,
+            queue_depth,
+            queue_stats,
+            stats_interval: None,
+            stats_logged: Instant::now(),
+            queue_depth_gauges: Vec::new(),
+            batches: (0..parallelism).map(|_| Vec::new()).collect(),
+            batch_size: 1,
+            flush_interval: Duration::ZERO,
+            oldest: vec![None; parallelism],
+            on_failure: OnFailure::default(),
+            failures,
+            tasks,
+            engine,
+        }
+    }
+
+    /// Spreads clients across workers with `sharding`, rather than by modulo
+    pub fn with_sharding(mut self, sharding: Sharding) -> Self {
+        self.router = Router::new(sharding, self.parallelism)
+            .with_rebalance_every(self.router.rebalance_every());
+        self
+    }
+
+    /// Looks for clients to move every `jobs` jobs when rebalancing
+    pub fn with_rebalance_every(mut self, jobs: u64) -> Self {
+        self.router = self.router.with_rebalance_every(jobs);
+        self
+    }
+
+    pub fn with_on_failure(mut self, on_failure: OnFailure) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    /// Sends jobs to each worker in batches of up to `batch_size`, waiting at most
+    /// `flush_interval` for a batch to fill
+    pub fn with_batching(mut self, batch_size: usize, flush_interval: Duration) -> Self {
+        self.batch_size = std::cmp::max(1, batch_size);
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Logs [`TaskPool::stats`] every `interval` while taking transactions. They're always
+    /// logged once every worker has finished
+    pub fn with_stats_interval(mut self, interval: Duration) -> Self {
+        self.stats_interval = Some(interval);
+        self
+    }
+
+    /// Reports each worker's queue depth, as of the last batch sent to it
+    pub fn with_metrics(mut self, metrics: &Metrics) -> Self {
+        self.queue_depth_gauges = (0..self.parallelism)
+            .map(|task| metrics.queue_depth(task))
+            .collect();
+        self
+    }
+
+    /// How busy each worker has been so far
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            queue_depth: self.queue_depth,
+            workers: self
+                .queue_stats
+                .iter()
+                .enumerate()
+                .map(|(task, stats)| WorkerStats {
+                    queued: self.senders.get(&task).map_or(0, Sender::len),
+                    peak_queued: stats.peak_queued,
+                    processed: stats.processed.load(Ordering::Relaxed),
+                    blocked: stats.blocked,
+                })
+                .collect(),
+        }
+    }
+
+    /// How long a job can wait in a batch before it's sent
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    /// Adds a job to its worker's batch, sending the batch if it's full
+    async fn push(&mut self, task: usize, job: Job) -> Result<()> {
+        self.batches[task].push(job);
+        self.oldest[task].get_or_insert_with(Instant::now);
+
+        if self.batches[task].len() >= self.batch_size {
+            self.send(task).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a worker's batch, if it has anything in it
+    async fn send(&mut self, task: usize) -> Result<()> {
+        if self.batches[task].is_empty() {
+            return Ok(());
+        }
+
+        let batch = mem::replace(&mut self.batches[task], Vec::with_capacity(self.batch_size));
+        self.oldest[task] = None;
+        let sender = self
+            .senders
+            .get(&task)
+            .expect("Must have created associated task");
+
+        let started = Instant::now();
+        sender
+            .send_async(batch)
+            .await
+            .map_err(|_| anyhow!("Engine worker {} has stopped", task))?;
+
+        let stats = &mut self.queue_stats[task];
+        stats.blocked += started.elapsed();
+        stats.peak_queued = std::cmp::max(stats.peak_queued, sender.len());
+        if let Some(gauge) = self.queue_depth_gauges.get(task) {
+            gauge.set(sender.len() as i64);
+        }
+
+        Ok(())
+    }
+
+    /// Moves a client between workers, behind everything already queued for it
+    async fn migrate(&mut self, client_move: Move) -> Result<()> {
+        let Move { client, from, to } = client_move;
+        let (user_state_sender, user_state_receiver) = bounded(1);
+
+        self.push(from, Job::Evict(client, user_state_sender))
+            .await?;
+        // The new worker will wait on this, so it can't sit in a batch
+        self.send(from).await?;
+        self.push(to, Job::Adopt(client, user_state_receiver))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sends every worker's batch, however full
+    pub async fn flush(&mut self) -> Result<()> {
+        for task in 0..self.parallelism {
+            self.send(task).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn add_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        if self.on_failure == OnFailure::Stop {
+            if let Ok(failure) = self.failures.try_recv() {
+                return Err(anyhow!("Stopped taking transactions, as {}", failure));
+            }
+        }
+
+        let associated_task = self.router.worker(transaction.client);
+        self.router.record(transaction.client);
+
+        let destination_task = match (transaction.kind, transaction.to_client) {
+            (Kind::Transfer, Some(to_client)) => {
+                self.router.record(to_client);
+                Some(self.router.worker(to_client))
+            }
+            _ => None,
+        };
+
+        match (destination_task, transaction.to_client) {
+            (Some(destination_task), Some(to_client)) if destination_task != associated_task => {
+                let (outcome_sender, outcome_receiver) = bounded(1);
+                let credit = Job::Credit(to_client, transaction.transaction_id, outcome_receiver);
+
+                self.push(associated_task, Job::Debit(transaction, outcome_sender))
+                    .await?;
+                // The destination's worker will wait on this, so it can't sit in a batch
+                self.send(associated_task).await?;
+                self.push(destination_task, credit).await?;
+            }
+            _ => {
+                self.push(associated_task, Job::Apply(transaction)).await?;
+            }
+        }
+
+        // Moving a client off a failed worker loses it. A worker may not have reported failing
+        // yet, so this can still happen, but there's no sense moving clients knowingly
+        if self.router.rebalance_due() && self.failures.is_empty() {
+            for client_move in self.router.rebalance() {
+                self.migrate(client_move).await?;
+            }
+        }
+
+        for task in 0..self.parallelism {
+            if matches!(self.oldest[task], Some(oldest) if oldest.elapsed() >= self.flush_interval)
+            {
+                self.send(task).await?;
+            }
+        }
+
+        if let Some(stats_interval) = self.stats_interval {
+            if self.stats_logged.elapsed() >= stats_interval {
+                self.stats().log();
+                self.stats_logged = Instant::now();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits for every worker to finish, failing if any of them did
+    pub async fn wait(self) -> Result<TransactionEngine> {
+        let (engine, failures) = self.wait_partial().await?;
+
+        if failures.is_empty() {
+            return Ok(engine);
+        }
+
+        let mut message = format!("{} engine workers failed or lost clients:", failures.len());
+        for failure in failures {
+            message.push_str(&format!("\n  - {}", failure));
+        }
+
+        Err(anyhow!(message))
+    }
+
+    /// Waits for every worker to finish, and merges the shards of those that didn't fail. The
+    /// engine is missing every client of a failed worker
+    pub async fn wait_partial(mut self) -> Result<(TransactionEngine, Vec<WorkerFailure>)> {
+        self.flush().await?;
+        self.senders.clear();
+
+        for task in self.tasks.drain(..) {
+            if let Some(shard) = task.await? {
+                self.engine.merge(shard)?;
+            }
+        }
+        self.stats().log();
+        for gauge in &self.queue_depth_gauges {
+            gauge.set(0);
+        }
+
+        // Every worker has finished, so has sent any failure
+        let failures = self.failures.drain().collect();
+        Ok((self.engine, failures))
+    }
+}
+
+#[cfg(test)]
+fn transaction(
+    kind: Kind,
+    client: UserId,
+    transaction_id: u32,
+    amount: Option<i64>,
+    to_client: Option<UserId>,
+) -> Transaction {
+    Transaction {
+        kind,
+        client,
+        transaction_id,
+        amount: amount.map(rust_decimal::Decimal::from),
+        currency: Default::default(),
+        to_currency: None,
+        rate: None,
+        to_client,
+        timestamp: None,
+    }
+}
+
+#[tokio::test]
+async fn opposing_transfers_across_workers() -> Result<()> {
+    use rust_decimal::Decimal;
+
+    let mut task_pool = TaskPool::new(TransactionEngine::default(), 1, 2)
+        .with_batching(16, Duration::from_secs(60));
+
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 0, 0, Some(100), None))
+        .await?;
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 1, 1, Some(100), None))
+        .await?;
+
+    // Clients 0 and 1 are on different workers, and each waits on the other in turn
+    let transfers = async {
+        for i in 0..1_000 {
+            let (from, to) = if i % 2 == 0 { (0, 1) } else { (1, 0) };
+            task_pool
+                .add_transaction(transaction(
+                    Kind::Transfer,
+                    from,
+                    2 + i,
+                    Some(100),
+                    Some(to),
+                ))
+                .await?;
+        }
+        Result::<()>::Ok(())
+    };
+    tokio::time::timeout(Duration::from_secs(10), transfers)
+        .await
+        .context("Transfers deadlocked")??;
+
+    // Client 1 only has 200 to withdraw if the transfer's credit landed first
+    task_pool
+        .add_transaction(transaction(Kind::Transfer, 0, 5_000, Some(100), Some(1)))
+        .await?;
+    task_pool
+        .add_transaction(transaction(Kind::Withdrawal, 1, 5_001, Some(200), None))
+        .await?;
+
+    // Client 0 has nothing left, so this must not credit client 1
+    task_pool
+        .add_transaction(transaction(Kind::Transfer, 0, 5_002, Some(50), Some(1)))
+        .await?;
+
+    let engine = tokio::time::timeout(Duration::from_secs(10), task_pool.wait())
+        .await
+        .context("Workers deadlocked")??;
+
+    let mut summaries = engine.current_account_states();
+    summaries.sort_by_key(|summary| summary.client);
+
+    assert_eq!(summaries[0].total, Decimal::ZERO);
+    assert_eq!(summaries[1].total, Decimal::ZERO);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_worker_is_left_out() -> Result<()> {
+    use rust_decimal::Decimal;
+
+    let mut task_pool = TaskPool::new(TransactionEngine::default(), 1, 2)
+        .with_batching(4, Duration::from_secs(60))
+        .with_on_failure(OnFailure::Continue);
+
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 0, 0, Some(100), None))
+        .await?;
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 2, 1, Some(100), None))
+        .await?;
+    // A deposit without an amount can't be applied, so client 1's worker fails
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 1, 2, None, None))
+        .await?;
+
+    // Both halves of a transfer are still answered, whichever side has failed
+    task_pool
+        .add_transaction(transaction(Kind::Transfer, 0, 3, Some(10), Some(1)))
+        .await?;
+    task_pool
+        .add_transaction(transaction(Kind::Transfer, 1, 4, Some(10), Some(0)))
+        .await?;
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 2, 5, Some(1), None))
+        .await?;
+
+    let (engine, failures) =
+        tokio::time::timeout(Duration::from_secs(10), task_pool.wait_partial())
+            .await
+            .context("Workers deadlocked")??;
+
+    assert_eq!(failures.len(), 1);
+    assert_eq!(
+        (
+            failures[0].worker,
+            failures[0].client,
+            failures[0].transaction_id
+        ),
+        (1, 1, Some(2))
+    );
+
+    let mut summaries = engine.current_account_states();
+    summaries.sort_by_key(|summary| summary.client);
+    assert_eq!(
+        summaries
+            .iter()
+            .map(|summary| (summary.client, summary.total))
+            .collect::<Vec<_>>(),
+        vec![(0, Decimal::from(90)), (2, Decimal::from(101))]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_moving_off_a_failed_worker_is_lost() -> Result<()> {
+    use rust_decimal::Decimal;
+
+    let mut task_pool = TaskPool::new(TransactionEngine::default(), 2, 2)
+        .with_batching(16, Duration::from_secs(60))
+        .with_sharding(Sharding::Rebalance)
+        .with_rebalance_every(6)
+        .with_on_failure(OnFailure::Continue);
+
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 2, 0, Some(100), None))
+        .await?;
+    for transaction_id in 1..4 {
+        task_pool
+            .add_transaction(transaction(Kind::Deposit, 0, transaction_id, Some(1), None))
+            .await?;
+    }
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 1, 4, Some(100), None))
+        .await?;
+    // Worker 0 fails on this, but hasn't been sent it yet as client 2 is moved off it
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 0, 5, None, None))
+        .await?;
+    assert_eq!(task_pool.router.worker(2), 1);
+
+    // Worker 1 skips client 2 from here on, but keeps applying client 1's transactions
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 2, 6, Some(1), None))
+        .await?;
+    task_pool
+        .add_transaction(transaction(Kind::Transfer, 1, 7, Some(10), Some(2)))
+        .await?;
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 1, 8, Some(1), None))
+        .await?;
+
+    let (engine, mut failures) =
+        tokio::time::timeout(Duration::from_secs(10), task_pool.wait_partial())
+            .await
+            .context("Workers deadlocked")??;
+
+    failures.sort_by_key(|failure| failure.worker);
+    assert_eq!(
+        failures
+            .iter()
+            .map(|failure| (failure.worker, failure.client, failure.client_lost))
+            .collect::<Vec<_>>(),
+        vec![(0, 0, false), (1, 2, true)]
+    );
+
+    let summaries = engine.current_account_states();
+    assert_eq!(
+        summaries
+            .iter()
+            .map(|summary| (summary.client, summary.total))
+            .collect::<Vec<_>>(),
+        vec![(1, Decimal::from(101))]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_worker_stops_the_pool() -> Result<()> {
+    let mut task_pool = TaskPool::new(TransactionEngine::default(), 1, 2);
+
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 1, 0, None, None))
+        .await?;
+
+    let stopped = async {
+        for transaction_id in 1.. {
+            if let Err(error) = task_pool
+                .add_transaction(transaction(Kind::Deposit, 0, transaction_id, Some(1), None))
+                .await
+            {
+                return error;
+            }
+        }
+        unreachable!("Transaction IDs ran out");
+    };
+    let error = tokio::time::timeout(Duration::from_secs(10), stopped)
+        .await
+        .context("The pool never stopped")?;
+
+    assert!(error.to_string().starts_with(
+        "Stopped taking transactions, as worker 1 failed on transaction 0 for client 1: There must be an amount"
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stats_count_jobs_per_worker() -> Result<()> {
+    let mut task_pool =
+        TaskPool::new(TransactionEngine::default(), 4, 2).with_batching(8, Duration::from_secs(60));
+
+    for i in 0..40 {
+        // Three quarters of the deposits are for client 0, on worker 0
+        let client = if i % 4 == 3 { 1 } else { 0 };
+        task_pool
+            .add_transaction(transaction(Kind::Deposit, client, i, Some(1), None))
+            .await?;
+    }
+    task_pool.flush().await?;
+
+    let stats = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let stats = task_pool.stats();
+            if stats
+                .workers
+                .iter()
+                .map(|worker| worker.processed)
+                .sum::<u64>()
+                == 40
+            {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .context("Workers never finished")?;
+
+    assert_eq!(stats.queue_depth, 4);
+    assert_eq!(
+        stats
+            .workers
+            .iter()
+            .map(|worker| (worker.queued, worker.processed))
+            .collect::<Vec<_>>(),
+        vec![(0, 30), (0, 10)]
+    );
+    assert!(stats.workers.iter().all(|worker| worker.peak_queued <= 4));
+    assert!((stats.skew() - 1.5).abs() < f64::EPSILON);
+
+    task_pool.wait().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn sending_a_full_batch_restarts_its_flush_interval() -> Result<()> {
+    let mut task_pool =
+        TaskPool::new(TransactionEngine::default(), 4, 1).with_batching(2, Duration::from_secs(60));
+
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 0, 1, Some(1), None))
+        .await?;
+    assert!(task_pool.oldest[0].is_some());
+
+    // A full batch is sent, so there's nothing left waiting on the interval
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 0, 2, Some(1), None))
+        .await?;
+    assert_eq!(task_pool.oldest, vec![None]);
+
+    let started = Instant::now();
+    task_pool
+        .add_transaction(transaction(Kind::Deposit, 0, 3, Some(1), None))
+        .await?;
+    assert!(task_pool.oldest[0].unwrap() >= started);
+
+    task_pool.flush().await?;
+    task_pool.wait().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn moved_clients_stay_in_order() -> Result<()> {
+    use rust_decimal::Decimal;
+
+    let mut task_pool = TaskPool::new(TransactionEngine::default(), 2, 2)
+        .with_batching(4, Duration::from_secs(60))
+        .with_sharding(Sharding::Rebalance)
+        .with_rebalance_every(20);
+
+    // Clients 0, 2 and 4 all start on worker 0, and client 0 is by far the busiest
+    let mut moved = false;
+    for round in 0..100 {
+        let id = round * 10;
+        for i in 0..3 {
+            task_pool
+                .add_transaction(transaction(Kind::Deposit, 0, id + i, Some(1), None))
+                .await?;
+        }
+        // The withdrawal fails if it's applied before the deposit
+        task_pool
+            .add_transaction(transaction(Kind::Deposit, 2, id + 3, Some(1), None))
+            .await?;
+        task_pool
+            .add_transaction(transaction(Kind::Withdrawal, 2, id + 4, Some(1), None))
+            .await?;
+        task_pool
+            .add_transaction(transaction(Kind::Deposit, 4, id + 5, Some(1), None))
+            .await?;
+        task_pool
+            .add_transaction(transaction(Kind::Transfer, 4, id + 6, Some(1), Some(2)))
+            .await?;
+
+        moved |= task_pool.router.worker(2) == 1 || task_pool.router.worker(4) == 1;
+    }
+    assert!(moved);
+
+    let engine = tokio::time::timeout(Duration::from_secs(10), task_pool.wait())
+        .await
+        .context("Workers deadlocked")??;
+
+    let mut summaries = engine.current_account_states();
+    summaries.sort_by_key(|summary| summary.client);
+    assert_eq!(
+        summaries
+            .iter()
+            .map(|summary| (summary.client, summary.total))
+            .collect::<Vec<_>>(),
+        vec![
+            (0, Decimal::from(300)),
+            (2, Decimal::from(100)),
+            (4, Decimal::ZERO)
+        ]
+    );
+
+    Ok(())
+}
@@ -1,10 +1,12 @@
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 pub type UserId = u16;
 pub type TransactionId = u32;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub kind: Kind,
@@ -16,10 +18,128 @@ pub struct Transaction {
 
     #[serde(with = "rust_decimal::serde::str_option")]
     pub amount: Option<Decimal>,
+
+    /// The currency of the amount. Inputs without a currency column are in the default currency
+    #[serde(default)]
+    pub currency: Currency,
+
+    /// The currency an exchange credits
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+
+    /// The rate for an exchange, in units of `to_currency` per unit of `currency`. Overrides the rate table when set
+    #[serde(default, with = "rust_decimal::serde::str_option")]
+    pub rate: Option<Decimal>,
+
+    /// The client a transfer credits
+    #[serde(default)]
+    pub to_client: Option<UserId>,
+
+    /// When the transaction happened
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+/// A currency code, like `USD` or `EUR`
+///
+/// Codes are case insensitive and stored in uppercase. An empty code is the default currency.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(from = "String", into = "String")]
+pub struct Currency(String);
+
+impl Currency {
+    /// The currency used when a transaction doesn't specify one
+    pub const DEFAULT: &'static str = "USD";
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self(String::from(Self::DEFAULT))
+    }
+}
+
+impl From<String> for Currency {
+    fn from(code: String) -> Self {
+        if code.is_empty() {
+            Self::default()
+        } else {
+            Self(code.to_ascii_uppercase())
+        }
+    }
+}
+
+impl From<&str> for Currency {
+    fn from(code: &str) -> Self {
+        Self::from(String::from(code))
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.0
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// When a transaction happened
+///
+/// Given either in RFC 3339 form, like `2022-07-09T12:30:00Z`, or as whole seconds since the
+/// Unix epoch. Always written out in RFC 3339 form, in UTC.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timestamp(DateTime<Utc>);
+
+impl Timestamp {
+    const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+    pub fn from_epoch_seconds(seconds: i64) -> Option<Self> {
+        Utc.timestamp_opt(seconds, 0).single().map(Self)
+    }
+
+    /// The UTC calendar day, counted in days since the Unix epoch
+    pub fn day(&self) -> i64 {
+        self.0.timestamp().div_euclid(Self::SECONDS_PER_DAY)
+    }
+
+    /// The whole seconds since an earlier timestamp, which are negative if it's actually later
+    pub fn seconds_since(&self, earlier: Timestamp) -> i64 {
+        self.0.timestamp() - earlier.0.timestamp()
+    }
+}
+
+impl TryFrom<String> for Timestamp {
+    type Error = String;
+
+    fn try_from(timestamp: String) -> Result<Self, Self::Error> {
+        match timestamp.parse::<i64>() {
+            Ok(seconds) => Self::from_epoch_seconds(seconds)
+                .ok_or_else(|| format!("Timestamp is out of range: {}", timestamp)),
+            Err(_) => DateTime::parse_from_rfc3339(&timestamp)
+                .map(|timestamp| Self(timestamp.with_timezone(&Utc)))
+                .map_err(|e| format!("Invalid timestamp {:?}: {}", timestamp, e)),
+        }
+    }
+}
+
+impl From<Timestamp> for String {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.0.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from(*self))
+    }
 }
 
 /// The possible kinds of transactions
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum Kind {
     /// A deposit is a credit to the client's asset account, meaning it should increase the available and total funds of the client account
@@ -36,4 +156,36 @@ pub enum Kind {
 
     /// A chargeback is the final state of a dispute and represents the client reversing a transaction. Funds that were held have now been withdrawn. This means that the clients held funds and total funds should decrease by the amount previously disputed. If a chargeback occurs the client's account should be immediately frozen.
     Chargeback,
+
+    /// An exchange converts funds between currencies. The amount is debited from the client's available funds in `currency`, and the converted amount is credited to their available funds in `to_currency`, using the row's rate or the rate table. Exchanges cannot be disputed, so they are never held or charged back.
+    Exchange,
+
+    /// A transfer moves funds from one client to another. The amount is debited from the client's available funds and credited to the available funds of `to_client`, in the same currency. If the client doesn't have enough available funds, neither client is changed. Transfers cannot be disputed.
+    Transfer,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 7] = [
+        Kind::Deposit,
+        Kind::Withdrawal,
+        Kind::Dispute,
+        Kind::Resolve,
+        Kind::Chargeback,
+        Kind::Exchange,
+        Kind::Transfer,
+    ];
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Exchange => "exchange",
+            Self::Transfer => "transfer",
+        })
+    }
 }
@@ -1,45 +1,140 @@
+use crate::accounts::{Account, Posting, TrialBalance};
+use crate::fees::FeeSchedule;
+use crate::ledger::LedgerEntry;
+use crate::limits::{LimitExceeded, Limits, LimitsConfig};
+use crate::metrics::Metrics;
 use crate::prelude::*;
-use crate::transaction::{Kind, Transaction, TransactionId, UserId};
-use dashmap::DashMap;
+use crate::rates::{check_rate, convert, RateTable};
+use crate::transaction::{Currency, Kind, Timestamp, Transaction, TransactionId, UserId};
+use crate::verify::Flows;
+use flume::Sender;
 use rust_decimal::Decimal;
 use serde::Serialize;
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 
 type UserTransactions = HashMap<TransactionId, Vec<TransactionTruncated>>;
 
 /// A user's account state
 #[derive(Default, Debug)]
 pub struct UserState {
-    /// The total funds that are held for dispute. This should be equal to total - available amounts
-    held: Decimal,
-
-    /// The total funds that are available or held. This should be equal to available + held
-    total: Decimal,
+    /// The user's funds, per currency
+    balances: HashMap<Currency, Balance>,
 
     /// Whether the account is locked. An account is locked if a charge back occurs
     locked: Lock,
 
     transactions: HashMap<TransactionId, Vec<TransactionTruncated>>,
+
+    /// How many of the client's own transactions have been applied
+    applied: usize,
+
+    /// Which of the client's applied transactions were withdrawals, by their position in
+    /// `applied`, and when they were made. Only tracked while a withdrawal count limit is set.
+    recent_withdrawals: VecDeque<(usize, Option<Timestamp>)>,
+
+    /// The day of the client's latest debit, and how much left their account that day in
+    /// each currency. Only tracked while a daily outflow limit is set.
+    daily_outflow: (i64, HashMap<Currency, Decimal>),
+
+    /// The latest timestamp of the client's transactions
+    last_timestamp: Option<Timestamp>,
 }
 
 impl UserState {
-    /// The total funds that are available for trading, staking, withdrawal, etc. This should be equal to the total - held amounts
-    fn available(&self) -> Decimal {
-        max(self.total - self.held, Decimal::ZERO)
+    /// How many withdrawals were applied within the limits' windows, counting back from a
+    /// withdrawal made at `now`. Withdrawals that have left the windows are forgotten.
+    fn withdrawals_within(&mut self, limits: &Limits, now: Option<Timestamp>) -> usize {
+        while let Some(&(position, then)) = self.recent_withdrawals.front() {
+            if limits.within_window(position, then, self.applied, now) {
+                break;
+            }
+            self.recent_withdrawals.pop_front();
+        }
+
+        self.recent_withdrawals.len()
+    }
+
+    /// Checks a debit made at `now` against the daily outflow limit, if there is one
+    fn check_outflow(
+        &mut self,
+        limits: &Limits,
+        currency: &Currency,
+        amount: Decimal,
+        now: Option<Timestamp>,
+    ) -> Result<(), LimitExceeded> {
+        if limits.max_daily_outflow.is_none() {
+            return Ok(());
+        }
+
+        let day = now.ok_or(LimitExceeded::UndatedOutflow)?.day();
+        if self.daily_outflow.0 != day {
+            self.daily_outflow = (day, HashMap::new());
+        }
+
+        let outflow_today = self
+            .daily_outflow
+            .1
+            .get(currency)
+            .copied()
+            .unwrap_or_default();
+        limits.check_outflow(amount, outflow_today)
+    }
+
+    /// Counts an applied debit towards the day's outflow, if there's a daily outflow limit
+    fn record_outflow(&mut self, limits: &Limits, currency: &Currency, amount: Decimal) {
+        if limits.max_daily_outflow.is_some() {
+            *self.daily_outflow.1.entry(currency.clone()).or_default() += amount;
+        }
+    }
+
+    /// The balance for a currency, which starts empty if the user has never used the currency
+    fn balance_mut(&mut self, currency: &Currency) -> &mut Balance {
+        self.balances.entry(currency.clone()).or_default()
     }
 }
 
-#[derive(Serialize)]
-pub struct UserSummary {
-    client: UserId,
+/// A user's funds in a single currency, kept in their available and held accounts
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    /// The client's available account. This goes below zero if a dispute holds more than was
+    /// available
     available: Decimal,
+
+    /// The client's held account, with the funds that are held for dispute
     held: Decimal,
-    total: Decimal,
-    locked: Lock,
 }
 
-#[derive(Default, Debug, Clone, Copy, Serialize)]
+impl Balance {
+    /// The total funds that are available for trading, staking, withdrawal, etc. This is
+    /// always the total less the held amounts, so it's negative while a dispute holds more
+    /// than the client had left
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    /// The total funds that are available or held
+    pub fn total(&self) -> Decimal {
+        self.available + self.held
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserSummary {
+    pub client: UserId,
+    pub currency: Currency,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: Lock,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum Lock {
     #[default]
     #[serde(rename(serialize = "false"))]
@@ -49,26 +144,383 @@ pub enum Lock {
     Locked,
 }
 
+/// The target of each transaction's span
+pub const SPAN_TARGET: &str = "payment_engine::spans";
+
+/// Why a transaction was ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Timestamped before the client's previous transaction, with [`TimestampPolicy::Reject`]
+    OutOfOrder,
+
+    /// Over one of the client's withdrawal limits
+    LimitExceeded,
+
+    /// More than the client has available
+    InsufficientFunds,
+
+    /// An amount of zero or less, which would move funds the wrong way
+    NonPositiveAmount,
+
+    /// An amount so large that its fee, the amount with its fee, or the balance it would be
+    /// added to, overflows
+    AmountOverflow,
+
+    /// An exchange between currencies without a rate
+    NoRate,
+
+    /// An exchange with a rate of zero or less
+    InvalidRate,
+
+    /// A transfer from a client to themselves
+    SelfTransfer,
+
+    /// A dispute of something other than a deposit
+    NotDisputable,
+
+    /// A dispute, resolve or chargeback of a deposit that doesn't exist
+    UnknownTransaction,
+
+    /// A dispute of a deposit that's already disputed
+    AlreadyDisputed,
+
+    /// A dispute of a deposit that was charged back
+    ChargedBack,
+
+    /// A dispute of a deposit older than the dispute window
+    DisputeWindowClosed,
+
+    /// A resolve or chargeback of a deposit that isn't disputed
+    NoDispute,
+}
+
+impl Rejection {
+    pub const ALL: [Rejection; 14] = [
+        Rejection::OutOfOrder,
+        Rejection::LimitExceeded,
+        Rejection::InsufficientFunds,
+        Rejection::NonPositiveAmount,
+        Rejection::AmountOverflow,
+        Rejection::NoRate,
+        Rejection::InvalidRate,
+        Rejection::SelfTransfer,
+        Rejection::NotDisputable,
+        Rejection::UnknownTransaction,
+        Rejection::AlreadyDisputed,
+        Rejection::ChargedBack,
+        Rejection::DisputeWindowClosed,
+        Rejection::NoDispute,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OutOfOrder => "out_of_order",
+            Self::LimitExceeded => "limit_exceeded",
+            Self::InsufficientFunds => "insufficient_funds",
+            Self::NonPositiveAmount => "non_positive_amount",
+            Self::AmountOverflow => "amount_overflow",
+            Self::NoRate => "no_rate",
+            Self::InvalidRate => "invalid_rate",
+            Self::SelfTransfer => "self_transfer",
+            Self::NotDisputable => "not_disputable",
+            Self::UnknownTransaction => "unknown_transaction",
+            Self::AlreadyDisputed => "already_disputed",
+            Self::ChargedBack => "charged_back",
+            Self::DisputeWindowClosed => "dispute_window_closed",
+            Self::NoDispute => "no_dispute",
+        }
+    }
+}
+
+/// What to do with a transaction timestamped before an earlier transaction for the same client
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    /// Apply it
+    Allow,
+
+    /// Apply it, with a warning
+    #[default]
+    Warn,
+
+    /// Ignore it, with a warning
+    Reject,
+
+    /// Stop processing with an error
+    Fail,
+}
+
+/// The house's side of the books, and the flows kept alongside them
+#[derive(Default, Debug)]
+struct House {
+    /// The balances of the house's accounts, per currency, where debits are positive. Clients'
+    /// accounts are kept in their [`UserState`]
+    accounts: HashMap<(Account, Currency), Decimal>,
+
+    /// What moved in and out of clients' accounts, per currency, kept apart from the accounts
+    /// so they can be checked against each other
+    flows: HashMap<Currency, Flows>,
+}
+
 #[derive(Default, Debug)]
 pub struct TransactionEngine {
-    user_states: DashMap<UserId, UserState>,
+    user_states: HashMap<UserId, UserState>,
+
+    /// Rates used by exchanges that don't carry their own rate
+    rates: RateTable,
+
+    /// Fees charged on deposits and withdrawals
+    fees: FeeSchedule,
+
+    /// Limits on withdrawals, per client
+    limits: LimitsConfig,
+
+    /// What to do with transactions that go back in time
+    timestamp_policy: TimestampPolicy,
+
+    /// How many seconds after a deposit it can be disputed. Unlimited if unset
+    dispute_window: Option<i64>,
+
+    house: House,
+
+    /// Where to send an entry for every applied transaction, if anywhere
+    ledger: Option<Sender<LedgerEntry>>,
+
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl TransactionEngine {
-    pub fn add_transaction(&self, new: Transaction) -> Result<()> {
-        if !self.user_states.contains_key(&new.client) {
-            self.user_states.insert(new.client, UserState::default());
+    pub fn with_rates(mut self, rates: RateTable) -> Self {
+        self.rates = rates;
+        self
+    }
+
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: LimitsConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_timestamp_policy(mut self, timestamp_policy: TimestampPolicy) -> Self {
+        self.timestamp_policy = timestamp_policy;
+        self
+    }
+
+    pub fn with_dispute_window_days(mut self, days: u32) -> Self {
+        self.dispute_window = Some(i64::from(days) * 24 * 60 * 60);
+        self
+    }
+
+    pub fn with_ledger(mut self, ledger: Sender<LedgerEntry>) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// An empty engine with the same configuration, for applying a share of the clients'
+    /// transactions. Shards are brought back together with [`TransactionEngine::merge`]
+    pub fn shard(&self) -> Self {
+        Self {
+            rates: self.rates.clone(),
+            fees: self.fees.clone(),
+            limits: self.limits.clone(),
+            timestamp_policy: self.timestamp_policy,
+            dispute_window: self.dispute_window,
+            ledger: self.ledger.clone(),
+            metrics: self.metrics.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Takes over a shard's clients, and adds its share of the house's balances to this
+    /// engine's. The shard can't have any of the same clients
+    pub fn merge(&mut self, shard: Self) -> Result<()> {
+        for (client, user_state) in shard.user_states {
+            if self.user_states.insert(client, user_state).is_some() {
+                return Err(anyhow!("Client {} is in more than one shard", client));
+            }
+        }
+
+        for (account, balance) in shard.house.accounts {
+            *self.house.accounts.entry(account).or_default() += balance;
+        }
+
+        for (currency, flows) in shard.house.flows {
+            *self.house.flows.entry(currency).or_default() += flows;
+        }
+
+        Ok(())
+    }
+
+    /// Takes a client out of this engine, to be [adopted](TransactionEngine::adopt) by
+    /// another shard. `None` if the client hasn't had any transactions yet
+    pub fn evict(&mut self, client: UserId) -> Option<UserState> {
+        self.user_states.remove(&client)
+    }
+
+    /// Takes over a client evicted from another shard
+    pub fn adopt(&mut self, client: UserId, user_state: UserState) -> Result<()> {
+        if self.user_states.insert(client, user_state).is_some() {
+            return Err(anyhow!("Client {} is in more than one shard", client));
+        }
+
+        Ok(())
+    }
+
+    pub fn add_transaction(&mut self, new: Transaction) -> Result<()> {
+        if let Some(credit) = self.apply_to_client(new)? {
+            self.credit(credit)?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies a transaction to the account of the client it's for
+    ///
+    /// A transfer only debits its source client here. If that succeeds the credit to the
+    /// destination client is returned, and must be passed to [`TransactionEngine::credit`].
+    pub fn apply_to_client(&mut self, new: Transaction) -> Result<Option<PendingCredit>> {
+        // At debug, so the default info filter skips the cost of a span for every transaction.
+        // Rejections name the client and transaction in their message either way
+        let _span = debug_span!(
+            target: SPAN_TARGET,
+            "transaction",
+            client = new.client,
+            tx = new.transaction_id,
+            kind = %new.kind
+        )
+        .entered();
+
+        if let Some(metrics) = &self.metrics {
+            metrics.transaction(new.kind);
         }
 
-        let mut user_state = self.user_states.get_mut(&new.client).unwrap();
-        match new.kind {
+        let user_state = self.user_states.entry(new.client).or_default();
+        let mut credit = None;
+
+        if let (Some(timestamp), Some(last_timestamp)) = (new.timestamp, user_state.last_timestamp)
+        {
+            if timestamp < last_timestamp {
+                match self.timestamp_policy {
+                    TimestampPolicy::Allow => {}
+                    TimestampPolicy::Warn => {
+                        warn!("Transaction {} for user {} is timestamped {}, before their previous transaction at {}", new.transaction_id, new.client, timestamp, last_timestamp);
+                    }
+                    TimestampPolicy::Reject => {
+                        warn!("Transaction {} for user {} is timestamped {}, before their previous transaction at {}, ignoring", new.transaction_id, new.client, timestamp, last_timestamp);
+                        return self.reject(Rejection::OutOfOrder);
+                    }
+                    TimestampPolicy::Fail => {
+                        return Err(anyhow!(
+                            "Transaction {} for user {} is timestamped {}, before their previous transaction at {}",
+                            new.transaction_id,
+                            new.client,
+                            timestamp,
+                            last_timestamp
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Transactions without a timestamp are treated as happening with the client's latest one
+        let now = new.timestamp.or(user_state.last_timestamp);
+
+        // The balances the transaction can change, before it's applied, for the ledger. A
+        // dispute, resolve or chargeback changes the disputed deposit's currency, whatever
+        // its own row says, and an exchange changes the currency it's to as well
+        let before = self.ledger.as_ref().map(|_| {
+            let currency = match new.kind {
+                Kind::Dispute | Kind::Resolve | Kind::Chargeback => get_first_transaction_by_kind(
+                    &user_state.transactions,
+                    new.transaction_id,
+                    Kind::Deposit,
+                )
+                .map_or(&new.currency, |deposit| &deposit.currency),
+                _ => &new.currency,
+            };
+            let balance = |currency: &Currency| {
+                user_state
+                    .balances
+                    .get(currency)
+                    .copied()
+                    .unwrap_or_default()
+            };
+
+            (balance(currency), new.to_currency.as_ref().map(balance))
+        });
+
+        // The currency and amount an exchange credited, for the ledger
+        let mut exchanged = None;
+
+        // What a chargeback wrote off
+        let mut written_off = Decimal::ZERO;
+
+        // The currency, amount and fee of what was applied, for the ledger
+        let (currency, amount, fee) = match new.kind {
             Kind::Deposit => {
-                user_state.total += new.amount.ok_or_else(|| {
+                let new_amount = new.amount.ok_or_else(|| {
                     anyhow!(
                         "There must be an amount for transactions of type deposit: {:?}",
                         new
                     )
                 })?;
+
+                // A negative deposit would take from the house instead
+                if new_amount <= Decimal::ZERO {
+                    warn!("Deposit failed! Cannot deposit {}, as it isn't positive (for transaction {} for user {}).", new_amount, new.transaction_id, new.client);
+                    return self.reject(Rejection::NonPositiveAmount);
+                }
+
+                // A fee can't take more than was deposited
+                let fee = match self.fees.fee(Kind::Deposit, new_amount) {
+                    Some(fee) => fee.min(new_amount),
+                    None => {
+                        warn!("Deposit failed! The fee on {} is too large to work out (for transaction {} for user {}).", new_amount, new.transaction_id, new.client);
+                        return self.reject(Rejection::AmountOverflow);
+                    }
+                };
+
+                // Nor can it take the client's or the house's balance past what can be held
+                let total = user_state
+                    .balances
+                    .get(&new.currency)
+                    .map_or(Decimal::ZERO, Balance::total);
+                let house_cash = self
+                    .house
+                    .accounts
+                    .get(&(Account::HouseCash, new.currency.clone()))
+                    .copied()
+                    .unwrap_or_default();
+                if total.checked_add(new_amount).is_none()
+                    || house_cash.checked_add(new_amount).is_none()
+                {
+                    warn!("Deposit failed! Depositing {} would overflow the balance (for transaction {} for user {}).", new_amount, new.transaction_id, new.client);
+                    return self.reject(Rejection::AmountOverflow);
+                }
+
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &new.currency,
+                        Account::HouseCash,
+                        Account::Available(new.client),
+                        new_amount - fee,
+                    ),
+                );
+                self.house.post(
+                    user_state,
+                    Posting::new(&new.currency, Account::HouseCash, Account::FeeIncome, fee),
+                );
+
+                (new.currency.clone(), new_amount, fee)
             }
             Kind::Withdrawal => {
                 let new_amount = new.amount.ok_or_else(|| {
@@ -78,13 +530,227 @@ impl TransactionEngine {
                     )
                 })?;
 
-                if user_state.available() < new_amount {
-                    warn!("Withdraw failed! User {} has only {} funds available, and cannot withdraw {} (for transaction {}).", new.client, user_state.available(), new_amount, new.transaction_id);
-                } else {
-                    user_state.total -= new_amount;
+                if new_amount <= Decimal::ZERO {
+                    warn!("Withdraw failed! Cannot withdraw {}, as it isn't positive (for transaction {} for user {}).", new_amount, new.transaction_id, new.client);
+                    return self.reject(Rejection::NonPositiveAmount);
+                }
+
+                let limits = self.limits.limits_for(new.client);
+                let recent_withdrawals = user_state.withdrawals_within(&limits, now);
+
+                if let Err(exceeded) = limits
+                    .check_withdrawal(new_amount, recent_withdrawals)
+                    .and_then(|_| user_state.check_outflow(&limits, &new.currency, new_amount, now))
+                {
+                    warn!("Withdraw rejected! {} for user {}, who cannot withdraw {} (for transaction {}).", exceeded, new.client, new_amount, new.transaction_id);
+                    return self.reject(Rejection::LimitExceeded);
+                }
+
+                let (fee, debit) = match self
+                    .fees
+                    .fee(Kind::Withdrawal, new_amount)
+                    .and_then(|fee| Some((fee, new_amount.checked_add(fee)?)))
+                {
+                    Some(fee_and_debit) => fee_and_debit,
+                    None => {
+                        warn!("Withdraw failed! The fee on {} is too large to work out (for transaction {} for user {}).", new_amount, new.transaction_id, new.client);
+                        return self.reject(Rejection::AmountOverflow);
+                    }
+                };
+                let balance = user_state.balance_mut(&new.currency);
+
+                if balance.available() < debit {
+                    warn!("Withdraw failed! User {} has only {} {} available, and cannot withdraw {} with a {} fee (for transaction {}).", new.client, balance.available(), new.currency, new_amount, fee, new.transaction_id);
+                    return self.reject(Rejection::InsufficientFunds);
+                }
+
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &new.currency,
+                        Account::Available(new.client),
+                        Account::HouseCash,
+                        new_amount,
+                    ),
+                );
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &new.currency,
+                        Account::Available(new.client),
+                        Account::FeeIncome,
+                        fee,
+                    ),
+                );
+
+                if limits.max_withdrawals.is_some() {
+                    let position = user_state.applied;
+                    user_state.recent_withdrawals.push_back((position, now));
+                }
+
+                user_state.record_outflow(&limits, &new.currency, new_amount);
+
+                (new.currency.clone(), new_amount, fee)
+            }
+            Kind::Exchange => {
+                let new_amount = new.amount.ok_or_else(|| {
+                    anyhow!(
+                        "There must be an amount for transactions of type exchange: {:?}",
+                        new
+                    )
+                })?;
+
+                let to_currency = new.to_currency.as_ref().ok_or_else(|| {
+                    anyhow!(
+                        "There must be a to_currency for transactions of type exchange: {:?}",
+                        new
+                    )
+                })?;
+
+                let rate = match new
+                    .rate
+                    .or_else(|| self.rates.rate(&new.currency, to_currency))
+                {
+                    Some(rate) => rate,
+                    None => {
+                        warn!("Exchange failed! No rate from {} to {}, ignoring: transaction {} for user {}", new.currency, to_currency, new.transaction_id, new.client);
+                        return self.reject(Rejection::NoRate);
+                    }
+                };
+
+                // The table's rates were checked as they were loaded, but not a row's own
+                if let Err(error) = check_rate(&new.currency, to_currency, rate) {
+                    warn!(
+                        "Exchange failed! {}, ignoring: transaction {} for user {}",
+                        error, new.transaction_id, new.client
+                    );
+                    return self.reject(Rejection::InvalidRate);
+                }
+
+                if new_amount <= Decimal::ZERO {
+                    warn!("Exchange failed! Cannot exchange {}, as it isn't positive (for transaction {} for user {}).", new_amount, new.transaction_id, new.client);
+                    return self.reject(Rejection::NonPositiveAmount);
+                }
+
+                let limits = self.limits.limits_for(new.client);
+                if let Err(exceeded) =
+                    user_state.check_outflow(&limits, &new.currency, new_amount, now)
+                {
+                    warn!("Exchange rejected! {} for user {}, who cannot exchange {} (for transaction {}).", exceeded, new.client, new_amount, new.transaction_id);
+                    return self.reject(Rejection::LimitExceeded);
+                }
+
+                let converted = convert(new_amount, rate)?;
+                let balance = user_state.balance_mut(&new.currency);
+
+                if balance.available() < new_amount {
+                    warn!("Exchange failed! User {} has only {} {} available, and cannot exchange {} (for transaction {}).", new.client, balance.available(), new.currency, new_amount, new.transaction_id);
+                    return self.reject(Rejection::InsufficientFunds);
+                }
+
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &new.currency,
+                        Account::Available(new.client),
+                        Account::Exchange,
+                        new_amount,
+                    ),
+                );
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        to_currency,
+                        Account::Exchange,
+                        Account::Available(new.client),
+                        converted,
+                    ),
+                );
+                exchanged = Some((to_currency.clone(), converted));
+                user_state.record_outflow(&limits, &new.currency, new_amount);
+
+                (new.currency.clone(), new_amount, Decimal::ZERO)
+            }
+            Kind::Transfer => {
+                let new_amount = new.amount.ok_or_else(|| {
+                    anyhow!(
+                        "There must be an amount for transactions of type transfer: {:?}",
+                        new
+                    )
+                })?;
+
+                let to_client = new.to_client.ok_or_else(|| {
+                    anyhow!(
+                        "There must be a to_client for transactions of type transfer: {:?}",
+                        new
+                    )
+                })?;
+
+                if to_client == new.client {
+                    warn!(
+                        "Cannot transfer to the same account, ignoring: transaction {} for user {}",
+                        new.transaction_id, new.client
+                    );
+                    return self.reject(Rejection::SelfTransfer);
+                }
+
+                // A negative transfer would take from the destination client instead
+                if new_amount <= Decimal::ZERO {
+                    warn!("Transfer failed! Cannot transfer {}, as it isn't positive (for transaction {} for user {}).", new_amount, new.transaction_id, new.client);
+                    return self.reject(Rejection::NonPositiveAmount);
+                }
+
+                let limits = self.limits.limits_for(new.client);
+                if let Err(exceeded) =
+                    user_state.check_outflow(&limits, &new.currency, new_amount, now)
+                {
+                    warn!("Transfer rejected! {} for user {}, who cannot transfer {} (for transaction {}).", exceeded, new.client, new_amount, new.transaction_id);
+                    return self.reject(Rejection::LimitExceeded);
+                }
+
+                let balance = user_state.balance_mut(&new.currency);
+
+                if balance.available() < new_amount {
+                    warn!("Transfer failed! User {} has only {} {} available, and cannot transfer {} (for transaction {}).", new.client, balance.available(), new.currency, new_amount, new.transaction_id);
+                    return self.reject(Rejection::InsufficientFunds);
                 }
+
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &new.currency,
+                        Account::Available(new.client),
+                        Account::InTransit,
+                        new_amount,
+                    ),
+                );
+                user_state.record_outflow(&limits, &new.currency, new_amount);
+                credit = Some(PendingCredit {
+                    to_client,
+                    transaction_id: new.transaction_id,
+                    amount: new_amount,
+                    currency: new.currency.clone(),
+                    timestamp: new.timestamp,
+                });
+
+                (new.currency.clone(), new_amount, Decimal::ZERO)
             }
             Kind::Dispute => {
+                if [Kind::Exchange, Kind::Transfer].into_iter().any(|kind| {
+                    get_first_transaction_by_kind(
+                        &user_state.transactions,
+                        new.transaction_id,
+                        kind,
+                    )
+                    .is_some()
+                }) {
+                    warn!(
+                        "Only deposits can be disputed, ignoring: transaction {} for user {}",
+                        new.transaction_id, new.client
+                    );
+                    return self.reject(Rejection::NotDisputable);
+                }
+
                 let transaction = get_first_transaction_by_kind(
                     &user_state.transactions,
                     new.transaction_id,
@@ -95,38 +761,74 @@ impl TransactionEngine {
                     Some(t) => t,
                     None => {
                         warn!("Cannot dispute a transaction that does not exist, ignoring: transaction {} for user {}", new.transaction_id,new.client);
-                        return Ok(());
+                        return self.reject(Rejection::UnknownTransaction);
                     }
                 };
 
                 if transaction.kind != Kind::Deposit {
                     warn!("First transaction (for a transaction ID) must be a deposit");
-                    return Ok(());
+                    return self.reject(Rejection::NotDisputable);
+                }
+
+                match dispute_state(&user_state.transactions, new.transaction_id) {
+                    Some(Kind::Dispute) => {
+                        warn!("Cannot dispute a transaction that is already disputed, ignoring: transaction {} for user {}", new.transaction_id, new.client);
+                        return self.reject(Rejection::AlreadyDisputed);
+                    }
+                    Some(Kind::Chargeback) => {
+                        warn!("Cannot dispute a transaction that was charged back, ignoring: transaction {} for user {}", new.transaction_id, new.client);
+                        return self.reject(Rejection::ChargedBack);
+                    }
+                    _ => {}
+                }
+
+                if let (Some(dispute_window), Some(now), Some(deposited)) =
+                    (self.dispute_window, now, transaction.timestamp)
+                {
+                    if now.seconds_since(deposited) > dispute_window {
+                        warn!("Cannot dispute a deposit made more than {} seconds ago, ignoring: transaction {} for user {}", dispute_window, new.transaction_id, new.client);
+                        return self.reject(Rejection::DisputeWindowClosed);
+                    }
                 }
 
+                // Only what the deposit credited, after its fee, is held
                 let amount = transaction
                     .amount
-                    .ok_or_else(|| anyhow!("Deposit should have an amount!"))?;
+                    .ok_or_else(|| anyhow!("Deposit should have an amount!"))?
+                    - transaction.fee;
 
-                if user_state.total < user_state.held + amount {
+                // Funds are always held in the currency of the disputed deposit
+                let currency = transaction.currency.clone();
+                let balance = user_state.balance_mut(&currency);
+
+                if balance.total() < balance.held + amount {
                     warn!(
-                        "Amount held is now greater than total! total={}, held={}, amount={}",
-                        user_state.total, user_state.held, amount
+                        "Amount held is now greater than total! total={}, held={}, amount={}, currency={}",
+                        balance.total(), balance.held, amount, currency
                     );
                 }
 
-                user_state.held += amount;
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &currency,
+                        Account::Available(new.client),
+                        Account::Held(new.client),
+                        amount,
+                    ),
+                );
+                if let Some(metrics) = &self.metrics {
+                    metrics.held(&currency, amount);
+                }
+
+                (currency, amount, Decimal::ZERO)
             }
             Kind::Resolve => {
-                if get_first_transaction_by_kind(
-                    &user_state.transactions,
-                    new.transaction_id,
-                    Kind::Dispute,
-                )
-                .is_none()
+                if dispute_state(&user_state.transactions, new.transaction_id)
+                    != Some(Kind::Dispute)
                 {
                     warn!("Cannot resolve a dispute that does not exist, ignoring: transaction {} for user {}", new.transaction_id, new.client);
-                    return Ok(());
+                    return self.reject(Rejection::NoDispute);
                 }
 
                 let deposit = get_first_transaction_by_kind(
@@ -139,34 +841,47 @@ impl TransactionEngine {
                     Some(t) => t,
                     None => {
                         warn!("Cannot resolve a dispute that has no corresponding deposit, ignoring: transaction {} for user {}", new.transaction_id, new.client);
-                        return Ok(());
+                        return self.reject(Rejection::UnknownTransaction);
                     }
                 };
 
                 let amount = deposit
                     .amount
-                    .ok_or_else(|| anyhow!("Deposit should have an amount!"))?;
+                    .ok_or_else(|| anyhow!("Deposit should have an amount!"))?
+                    - deposit.fee;
+
+                let currency = deposit.currency.clone();
+                let balance = user_state.balance_mut(&currency);
 
-                if user_state.held - amount < Decimal::ZERO {
+                if balance.held - amount < Decimal::ZERO {
                     return Err(anyhow!(
                         "amount is more than held! amount={} held={}",
-                        user_state.held,
+                        balance.held,
                         amount
                     ));
                 }
 
-                user_state.held -= amount;
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &currency,
+                        Account::Held(new.client),
+                        Account::Available(new.client),
+                        amount,
+                    ),
+                );
+                if let Some(metrics) = &self.metrics {
+                    metrics.held(&currency, -amount);
+                }
+
+                (currency, amount, Decimal::ZERO)
             }
             Kind::Chargeback => {
-                if get_first_transaction_by_kind(
-                    &user_state.transactions,
-                    new.transaction_id,
-                    Kind::Dispute,
-                )
-                .is_none()
+                if dispute_state(&user_state.transactions, new.transaction_id)
+                    != Some(Kind::Dispute)
                 {
                     warn!("Cannot chargeback a dispute that does not exist, ignoring: transaction {} for user {}", new.transaction_id, new.client);
-                    return Ok(());
+                    return self.reject(Rejection::NoDispute);
                 }
 
                 let deposit = get_first_transaction_by_kind(
@@ -179,35 +894,111 @@ impl TransactionEngine {
                     Some(t) => t,
                     None => {
                         warn!("Cannot chargeback a dispute that has no corresponding deposit, ignoring: transaction {} for user {}", new.transaction_id, new.client);
-                        return Ok(());
+                        return self.reject(Rejection::UnknownTransaction);
                     }
                 };
 
                 let amount = deposit
                     .amount
-                    .ok_or_else(|| anyhow!("Deposit should have an amount!"))?;
+                    .ok_or_else(|| anyhow!("Deposit should have an amount!"))?
+                    - deposit.fee;
 
-                if user_state.held - amount < Decimal::ZERO {
+                let currency = deposit.currency.clone();
+                let deposit_fee = deposit.fee;
+                let balance = user_state.balance_mut(&currency);
+
+                if balance.held - amount < Decimal::ZERO {
                     return Err(anyhow!(
                         "amount is more than held! amount={} held={}",
-                        user_state.held,
+                        balance.held,
                         amount
                     ));
                 }
 
-                if user_state.total - amount < Decimal::ZERO {
-                    warn!("user account {} went negative", new.client);
-                }
+                // The client can't be made to owe more than they have, so the rest is a loss
+                let shortfall = max(amount - balance.total(), Decimal::ZERO);
 
-                user_state.held -= amount;
-                user_state.total -= amount;
-                user_state.locked = Lock::Locked;
-            }
-        }
+                written_off = shortfall;
 
-        // If previous match ran (and didn't return out), then we can add the transaction to
-        // the transaction list for the user
-        let user_transactions = user_state
+                if !shortfall.is_zero() {
+                    warn!(
+                        "user account {} went negative in {}, writing off {}",
+                        new.client, currency, shortfall
+                    );
+                }
+
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &currency,
+                        Account::Held(new.client),
+                        Account::HouseCash,
+                        amount,
+                    ),
+                );
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &currency,
+                        Account::ChargebackLoss,
+                        Account::Available(new.client),
+                        shortfall,
+                    ),
+                );
+                if let Some(metrics) = &self.metrics {
+                    metrics.held(&currency, -amount);
+                    if user_state.locked == Lock::Unlocked {
+                        metrics.locked_account();
+                    }
+                }
+                user_state.locked = Lock::Locked;
+
+                // The fee for the deposit is refunded out of the house's fee income
+                self.house.post(
+                    user_state,
+                    Posting::new(
+                        &currency,
+                        Account::FeeIncome,
+                        Account::HouseCash,
+                        deposit_fee,
+                    ),
+                );
+
+                (currency, amount, -deposit_fee)
+            }
+        };
+
+        self.house.record_flows(&currency, |flows| match new.kind {
+            Kind::Deposit => {
+                flows.deposited += amount;
+                flows.fees += fee;
+            }
+            Kind::Withdrawal => {
+                flows.withdrawn += amount;
+                flows.fees += fee;
+            }
+            Kind::Exchange => flows.exchanged_out += amount,
+            Kind::Transfer => flows.transferred_out += amount,
+            Kind::Dispute | Kind::Resolve => {}
+            Kind::Chargeback => {
+                // The refunded fee is negative
+                flows.charged_back += amount - fee;
+                flows.fees += fee;
+                flows.written_off += written_off;
+            }
+        });
+
+        if let Some((to_currency, converted)) = &exchanged {
+            self.house
+                .record_flows(to_currency, |flows| flows.exchanged_in += *converted);
+        }
+
+        user_state.applied += 1;
+        user_state.last_timestamp = max(user_state.last_timestamp, new.timestamp);
+
+        // If previous match ran (and didn't return out), then we can add the transaction to
+        // the transaction list for the user
+        let user_transactions = user_state
             .transactions
             .entry(new.transaction_id)
             .or_default();
@@ -215,22 +1006,240 @@ impl TransactionEngine {
         user_transactions.push(TransactionTruncated {
             kind: new.kind,
             amount: new.amount,
+            currency: new.currency,
+            fee,
+            timestamp: new.timestamp,
         });
 
+        if let (Some(ledger), Some((before, exchanged_before))) = (&self.ledger, before) {
+            let entry = LedgerEntry::new(new.client, new.transaction_id, new.kind, new.timestamp);
+
+            let movements = std::iter::once((currency, amount, fee, before)).chain(exchanged.map(
+                |(currency, converted)| {
+                    (
+                        currency,
+                        converted,
+                        Decimal::ZERO,
+                        exchanged_before.unwrap_or_default(),
+                    )
+                },
+            ));
+
+            for (currency, amount, fee, before) in movements {
+                let after = *user_state.balance_mut(&currency);
+
+                send_to_ledger(
+                    ledger,
+                    entry
+                        .clone()
+                        .movement(currency, amount, fee)
+                        .balances(&before, &after),
+                )?;
+            }
+        }
+
+        Ok(credit)
+    }
+
+    /// Counts a rejected transaction, which is otherwise ignored
+    fn reject(&self, rejection: Rejection) -> Result<Option<PendingCredit>> {
+        if let Some(metrics) = &self.metrics {
+            metrics.rejection(rejection);
+        }
+
+        Ok(None)
+    }
+
+    /// Credits the destination client of a transfer that was debited by [`TransactionEngine::apply_to_client`]
+    pub fn credit(&mut self, credit: PendingCredit) -> Result<()> {
+        let _span = debug_span!(
+            target: SPAN_TARGET,
+            "credit",
+            client = credit.to_client,
+            tx = credit.transaction_id
+        )
+        .entered();
+
+        let user_state = self.user_states.entry(credit.to_client).or_default();
+
+        let before = *user_state.balance_mut(&credit.currency);
+        self.house.post(
+            user_state,
+            Posting::new(
+                &credit.currency,
+                Account::InTransit,
+                Account::Available(credit.to_client),
+                credit.amount,
+            ),
+        );
+        let after = *user_state.balance_mut(&credit.currency);
+        self.house.record_flows(&credit.currency, |flows| {
+            flows.transferred_in += credit.amount
+        });
+
+        user_state.last_timestamp = max(user_state.last_timestamp, credit.timestamp);
+
+        user_state
+            .transactions
+            .entry(credit.transaction_id)
+            .or_default()
+            .push(TransactionTruncated {
+                kind: Kind::Transfer,
+                amount: Some(credit.amount),
+                currency: credit.currency.clone(),
+                fee: Decimal::ZERO,
+                timestamp: credit.timestamp,
+            });
+
+        if let Some(ledger) = &self.ledger {
+            send_to_ledger(
+                ledger,
+                LedgerEntry::new(
+                    credit.to_client,
+                    credit.transaction_id,
+                    Kind::Transfer,
+                    credit.timestamp,
+                )
+                .movement(credit.currency, credit.amount, Decimal::ZERO)
+                .balances(&before, &after),
+            )?;
+        }
+
         Ok(())
     }
-    pub fn current_account_states(&self) -> Vec<UserSummary> {
-        self.user_states
+
+    /// What moved in and out of clients' accounts, per currency
+    pub fn flows(&self) -> Vec<(Currency, Flows)> {
+        self.house
+            .flows
             .iter()
-            .map(|state| UserSummary {
-                client: *state.key(),
-                available: state.available(),
-                held: state.held,
-                total: state.total,
-                locked: state.locked,
+            .map(|(currency, flows)| (currency.clone(), flows.clone()))
+            .collect()
+    }
+
+    /// The balances of the house's accounts, per currency, in each account's normal direction
+    pub fn house_balances(&self) -> Vec<(Account, Currency, Decimal)> {
+        self.house
+            .accounts
+            .iter()
+            .map(|((account, currency), balance)| {
+                let balance = if account.is_debit_normal() {
+                    *balance
+                } else {
+                    -*balance
+                };
+
+                (*account, currency.clone(), balance)
             })
             .collect()
     }
+
+    /// The fees booked to the house's fee income, per currency
+    pub fn fees_collected(&self) -> Vec<(Currency, Decimal)> {
+        self.house_balances()
+            .into_iter()
+            .filter(|(account, _, _)| *account == Account::FeeIncome)
+            .map(|(_, currency, fees)| (currency, fees))
+            .collect()
+    }
+
+    /// The debits and credits across every account, per currency
+    ///
+    /// Every currency should balance. If one doesn't, money was created or destroyed.
+    pub fn trial_balance(&self) -> Vec<TrialBalance> {
+        let mut trial_balances: HashMap<Currency, TrialBalance> = HashMap::new();
+        let mut add = |currency: &Currency, balance: Decimal| {
+            trial_balances
+                .entry(currency.clone())
+                .or_insert_with(|| TrialBalance::new(currency.clone()))
+                .add(balance);
+        };
+
+        for state in self.user_states.values() {
+            for (currency, balance) in state.balances.iter() {
+                add(currency, -balance.available);
+                add(currency, -balance.held);
+            }
+        }
+
+        for ((_, currency), balance) in self.house.accounts.iter() {
+            add(currency, *balance);
+        }
+
+        let mut trial_balances: Vec<_> = trial_balances.into_values().collect();
+        trial_balances.sort_by(|a, b| a.currency.cmp(&b.currency));
+        trial_balances
+    }
+
+    /// Summaries of every account, with one summary per client and currency
+    pub fn current_account_states(&self) -> Vec<UserSummary> {
+        let mut summaries = Vec::new();
+
+        for (client, state) in self.user_states.iter() {
+            if state.balances.is_empty() {
+                // Clients that never moved funds still get reported
+                summaries.push(UserSummary {
+                    client: *client,
+                    currency: Currency::default(),
+                    available: Decimal::ZERO,
+                    held: Decimal::ZERO,
+                    total: Decimal::ZERO,
+                    locked: state.locked,
+                });
+            }
+
+            for (currency, balance) in state.balances.iter() {
+                summaries.push(UserSummary {
+                    client: *client,
+                    currency: currency.clone(),
+                    available: balance.available(),
+                    held: balance.held,
+                    total: balance.total(),
+                    locked: state.locked,
+                });
+            }
+        }
+
+        summaries
+    }
+}
+
+impl House {
+    /// Posts to the books, where any client accounts belong to the given client's state
+    fn post(&mut self, user_state: &mut UserState, posting: Posting) {
+        if posting.amount.is_zero() {
+            return;
+        }
+
+        for (account, amount) in [
+            (posting.debit, posting.amount),
+            (posting.credit, -posting.amount),
+        ] {
+            match account {
+                // Clients' accounts are kept as what the house owes them, so are credits
+                Account::Available(_) => {
+                    user_state.balance_mut(&posting.currency).available -= amount
+                }
+                Account::Held(_) => user_state.balance_mut(&posting.currency).held -= amount,
+                _ => {
+                    *self
+                        .accounts
+                        .entry((account, posting.currency.clone()))
+                        .or_default() += amount
+                }
+            }
+        }
+    }
+
+    fn record_flows(&mut self, currency: &Currency, record: impl FnOnce(&mut Flows)) {
+        record(self.flows.entry(currency.clone()).or_default());
+    }
+}
+
+fn send_to_ledger(ledger: &Sender<LedgerEntry>, entry: LedgerEntry) -> Result<()> {
+    ledger
+        .send(entry)
+        .map_err(|_| anyhow!("The ledger writer has stopped"))
 }
 
 fn get_first_transaction_by_kind(
@@ -243,10 +1252,37 @@ fn get_first_transaction_by_kind(
         .and_then(|v| v.iter().find(|t| t.kind == kind))
 }
 
+/// The latest dispute, resolve or chargeback for a transaction, if any. A transaction is
+/// under dispute only if this is a dispute
+fn dispute_state(
+    user_transactions: &UserTransactions,
+    transaction_id: TransactionId,
+) -> Option<Kind> {
+    user_transactions.get(&transaction_id).and_then(|v| {
+        v.iter()
+            .rev()
+            .map(|t| t.kind)
+            .find(|kind| matches!(kind, Kind::Dispute | Kind::Resolve | Kind::Chargeback))
+    })
+}
+
+/// The second half of a transfer, crediting the destination client
+#[derive(Debug)]
+pub struct PendingCredit {
+    pub to_client: UserId,
+    pub transaction_id: TransactionId,
+    pub amount: Decimal,
+    pub currency: Currency,
+    pub timestamp: Option<Timestamp>,
+}
+
 #[derive(Debug)]
 struct TransactionTruncated {
     pub kind: Kind,
     pub amount: Option<Decimal>,
+    pub currency: Currency,
+    pub fee: Decimal,
+    pub timestamp: Option<Timestamp>,
 }
 
 #[test]
@@ -263,13 +1299,18 @@ fn input_from_pdf() {
 
 #[test]
 fn parsed_input_from_pdf() -> Result<()> {
-    let engine = TransactionEngine::default();
+    let mut engine = TransactionEngine::default();
 
     engine.add_transaction(Transaction {
         kind: Kind::Deposit,
         client: 1,
         transaction_id: 1,
         amount: Some(Decimal::from(1)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
     })?;
 
     engine.add_transaction(Transaction {
@@ -277,6 +1318,11 @@ fn parsed_input_from_pdf() -> Result<()> {
         client: 2,
         transaction_id: 2,
         amount: Some(Decimal::from(2)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
     })?;
 
     engine.add_transaction(Transaction {
@@ -284,6 +1330,11 @@ fn parsed_input_from_pdf() -> Result<()> {
         client: 1,
         transaction_id: 3,
         amount: Some(Decimal::from(2)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
     })?;
 
     engine.add_transaction(Transaction {
@@ -291,6 +1342,11 @@ fn parsed_input_from_pdf() -> Result<()> {
         client: 1,
         transaction_id: 4,
         amount: Some(Decimal::from_str_exact("1.5").unwrap()),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
     })?;
 
     engine.add_transaction(Transaction {
@@ -298,6 +1354,11 @@ fn parsed_input_from_pdf() -> Result<()> {
         client: 2,
         transaction_id: 5,
         amount: Some(Decimal::from(3)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
     })?;
 
     Ok(())
@@ -305,20 +1366,718 @@ fn parsed_input_from_pdf() -> Result<()> {
 
 #[test]
 fn simple_dispute() -> Result<()> {
-    let engine = TransactionEngine::default();
+    let mut engine = TransactionEngine::default();
     engine.add_transaction(Transaction {
         kind: Kind::Deposit,
         client: 0,
         transaction_id: 0,
         amount: Some(Decimal::from(2)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Dispute,
+        client: 0,
+        transaction_id: 0,
+        amount: None,
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    Ok(())
+}
+
+#[test]
+fn dispute_holds_in_deposit_currency() -> Result<()> {
+    let mut engine = TransactionEngine::default();
+    engine.add_transaction(Transaction {
+        kind: Kind::Deposit,
+        client: 0,
+        transaction_id: 0,
+        amount: Some(Decimal::from(2)),
+        currency: Currency::from("EUR"),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Deposit,
+        client: 0,
+        transaction_id: 1,
+        amount: Some(Decimal::from(5)),
+        currency: Currency::from("USD"),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Dispute,
+        client: 0,
+        transaction_id: 0,
+        amount: None,
+        currency: Currency::from("USD"),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    let user_state = engine.user_states.get(&0).unwrap();
+    assert_eq!(
+        user_state.balances[&Currency::from("EUR")].held,
+        Decimal::from(2)
+    );
+    assert_eq!(
+        user_state.balances[&Currency::from("USD")].held,
+        Decimal::ZERO
+    );
+    assert_eq!(
+        user_state.balances[&Currency::from("USD")].available(),
+        Decimal::from(5)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn exchange_between_currencies() -> Result<()> {
+    let mut engine = TransactionEngine::default().with_rates(RateTable::from_reader(
+        "from,to,rate\nEUR,USD,1.1\n".as_bytes(),
+    )?);
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Deposit,
+        client: 0,
+        transaction_id: 0,
+        amount: Some(Decimal::from(10)),
+        currency: Currency::from("EUR"),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Exchange,
+        client: 0,
+        transaction_id: 1,
+        amount: Some(Decimal::from(4)),
+        currency: Currency::from("EUR"),
+        to_currency: Some(Currency::from("USD")),
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    // Not enough EUR left, so this is ignored
+    engine.add_transaction(Transaction {
+        kind: Kind::Exchange,
+        client: 0,
+        transaction_id: 2,
+        amount: Some(Decimal::from(7)),
+        currency: Currency::from("EUR"),
+        to_currency: Some(Currency::from("USD")),
+        rate: Some(Decimal::from(2)),
+        to_client: None,
+        timestamp: None,
     })?;
 
     engine.add_transaction(Transaction {
         kind: Kind::Dispute,
         client: 0,
+        transaction_id: 1,
+        amount: None,
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    let user_state = engine.user_states.get(&0).unwrap();
+    assert_eq!(
+        user_state.balances[&Currency::from("EUR")].total(),
+        Decimal::from(6)
+    );
+    assert_eq!(
+        user_state.balances[&Currency::from("USD")].total(),
+        Decimal::from_str_exact("4.4")?
+    );
+    assert_eq!(
+        user_state.balances[&Currency::from("USD")].held,
+        Decimal::ZERO
+    );
+
+    Ok(())
+}
+
+#[test]
+fn fees_refunded_on_chargeback() -> Result<()> {
+    let mut engine = TransactionEngine::default().with_fees(FeeSchedule::from_reader(
+        "type,flat,percent\ndeposit,1,\nwithdrawal,,10\n".as_bytes(),
+    )?);
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Deposit,
+        client: 0,
+        transaction_id: 0,
+        amount: Some(Decimal::from(11)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Deposit,
+        client: 0,
+        transaction_id: 1,
+        amount: Some(Decimal::from(21)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    // 10 plus a 1 fee
+    engine.add_transaction(Transaction {
+        kind: Kind::Withdrawal,
+        client: 0,
+        transaction_id: 2,
+        amount: Some(Decimal::from(10)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Dispute,
+        client: 0,
+        transaction_id: 0,
+        amount: None,
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    {
+        let user_state = engine.user_states.get(&0).unwrap();
+        assert_eq!(
+            user_state.balances[&Currency::default()].total(),
+            Decimal::from(19)
+        );
+        assert_eq!(
+            user_state.balances[&Currency::default()].held,
+            Decimal::from(10)
+        );
+    }
+    assert_eq!(
+        engine.fees_collected(),
+        vec![(Currency::default(), Decimal::from(3))]
+    );
+
+    engine.add_transaction(Transaction {
+        kind: Kind::Chargeback,
+        client: 0,
         transaction_id: 0,
         amount: None,
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
     })?;
 
+    let user_state = engine.user_states.get(&0).unwrap();
+    assert_eq!(
+        user_state.balances[&Currency::default()].total(),
+        Decimal::from(9)
+    );
+    assert_eq!(
+        user_state.balances[&Currency::default()].held,
+        Decimal::ZERO
+    );
+    assert_eq!(
+        engine.fees_collected(),
+        vec![(Currency::default(), Decimal::from(2))]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn withdrawal_limits() -> Result<()> {
+    let mut engine = TransactionEngine::default().with_limits(LimitsConfig::from_reader(
+        "client,max_withdrawal,max_withdrawals,window\n,5,2,3\n".as_bytes(),
+    )?);
+
+    let mut transaction_id = 0;
+    let mut add = |kind: Kind, amount: i64| {
+        transaction_id += 1;
+        engine.add_transaction(Transaction {
+            kind,
+            client: 0,
+            transaction_id,
+            amount: Some(Decimal::from(amount)),
+            currency: Currency::default(),
+            to_currency: None,
+            rate: None,
+            to_client: None,
+            timestamp: None,
+        })
+    };
+
+    add(Kind::Deposit, 100)?;
+    // Over the amount limit
+    add(Kind::Withdrawal, 6)?;
+    add(Kind::Withdrawal, 1)?;
+    add(Kind::Withdrawal, 1)?;
+    // A third withdrawal within 3 transactions
+    add(Kind::Withdrawal, 1)?;
+    add(Kind::Deposit, 1)?;
+    // The first withdrawal is now outside the window
+    add(Kind::Withdrawal, 1)?;
+
+    let user_state = engine.user_states.get(&0).unwrap();
+    assert_eq!(
+        user_state.balances[&Currency::default()].total(),
+        Decimal::from(98)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn daily_outflow_counts_every_debit_per_currency() -> Result<()> {
+    let mut engine = TransactionEngine::default().with_limits(LimitsConfig::from_reader(
+        "client,max_daily_outflow\n,10\n".as_bytes(),
+    )?);
+
+    let day = 24 * 60 * 60;
+    let mut transaction_id = 0;
+    let mut add = |kind: Kind, client, amount: i64, currency: &str, seconds: Option<i64>| {
+        transaction_id += 1;
+        engine.add_transaction(Transaction {
+            kind,
+            client,
+            transaction_id,
+            amount: Some(Decimal::from(amount)),
+            currency: Currency::from(currency),
+            to_currency: (kind == Kind::Exchange).then(|| Currency::from("EUR")),
+            rate: (kind == Kind::Exchange).then_some(Decimal::ONE),
+            to_client: (kind == Kind::Transfer).then_some(1),
+            timestamp: seconds.and_then(Timestamp::from_epoch_seconds),
+        })
+    };
+
+    add(Kind::Deposit, 0, 100, "USD", Some(day))?;
+    add(Kind::Deposit, 0, 100, "EUR", Some(day))?;
+    add(Kind::Withdrawal, 0, 4, "USD", Some(day))?;
+    add(Kind::Transfer, 0, 4, "USD", Some(day))?;
+    // Over the limit, counting the withdrawal and transfer
+    add(Kind::Exchange, 0, 4, "USD", Some(day))?;
+    // Each currency has a limit of its own
+    add(Kind::Withdrawal, 0, 10, "EUR", Some(day))?;
+    // The next day
+    add(Kind::Exchange, 0, 4, "USD", Some(2 * day))?;
+
+    // No telling which day these are on
+    add(Kind::Deposit, 2, 100, "USD", None)?;
+    add(Kind::Withdrawal, 2, 1, "USD", None)?;
+
+    let total =
+        |client, currency| engine.user_states[&client].balances[&Currency::from(currency)].total();
+    assert_eq!(total(0, "USD"), Decimal::from(88));
+    assert_eq!(total(0, "EUR"), Decimal::from(94));
+    assert_eq!(total(1, "USD"), Decimal::from(4));
+    assert_eq!(total(2, "USD"), Decimal::from(100));
+
+    Ok(())
+}
+
+#[test]
+fn timestamp_rules() -> Result<()> {
+    let mut engine = TransactionEngine::default()
+        .with_timestamp_policy(TimestampPolicy::Reject)
+        .with_dispute_window_days(1)
+        .with_limits(LimitsConfig::from_reader(
+            "client,max_daily_outflow\n,10\n".as_bytes(),
+        )?);
+
+    let day = 24 * 60 * 60;
+    let mut transaction_id = 0;
+    let mut add = |kind: Kind, amount: Option<i64>, seconds: i64| {
+        transaction_id += 1;
+        engine.add_transaction(Transaction {
+            kind,
+            client: 0,
+            transaction_id: if kind == Kind::Dispute {
+                1
+            } else {
+                transaction_id
+            },
+            amount: amount.map(Decimal::from),
+            currency: Currency::default(),
+            to_currency: None,
+            rate: None,
+            to_client: None,
+            timestamp: Timestamp::from_epoch_seconds(seconds),
+        })
+    };
+
+    add(Kind::Deposit, Some(100), 10 * day)?;
+    add(Kind::Withdrawal, Some(6), 10 * day + 1)?;
+    // Over the daily limit
+    add(Kind::Withdrawal, Some(6), 10 * day + 2)?;
+    // Before the last transaction
+    add(Kind::Withdrawal, Some(1), 10 * day)?;
+    // The next day
+    add(Kind::Withdrawal, Some(6), 11 * day)?;
+    // Too long after the deposit
+    add(Kind::Dispute, None, 11 * day + 1)?;
+
+    let user_state = engine.user_states.get(&0).unwrap();
+    assert_eq!(
+        user_state.balances[&Currency::default()].total(),
+        Decimal::from(88)
+    );
+    assert_eq!(
+        user_state.balances[&Currency::default()].held,
+        Decimal::ZERO
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ledger_balances_before_and_after() -> Result<()> {
+    let (ledger, entries) = flume::unbounded();
+    let mut engine = TransactionEngine::default()
+        .with_rates(RateTable::from_reader(
+            "from,to,rate\nEUR,USD,1.1\n".as_bytes(),
+        )?)
+        .with_ledger(ledger);
+
+    for (transaction_id, kind, amount, to_currency) in [
+        (0, Kind::Deposit, Some(10), None),
+        (0, Kind::Dispute, None, None),
+        (0, Kind::Resolve, None, None),
+        (1, Kind::Exchange, Some(4), Some(Currency::from("USD"))),
+    ] {
+        engine.add_transaction(Transaction {
+            kind,
+            client: 0,
+            transaction_id,
+            amount: amount.map(Decimal::from),
+            // Disputes and resolves leave the currency out, but act on the deposit's
+            currency: match kind {
+                Kind::Dispute | Kind::Resolve => Currency::default(),
+                _ => Currency::from("EUR"),
+            },
+            to_currency,
+            rate: None,
+            to_client: None,
+            timestamp: None,
+        })?;
+    }
+    drop(engine);
+
+    let balances = |entry: &LedgerEntry| {
+        [
+            entry.available_before,
+            entry.held_before,
+            entry.total_before,
+            entry.available_after,
+            entry.held_after,
+            entry.total_after,
+        ]
+        .map(|amount| amount.to_string())
+    };
+
+    let entries: Vec<LedgerEntry> = entries.drain().collect();
+    assert_eq!(entries.len(), 5);
+    assert_eq!(balances(&entries[0]), ["0", "0", "0", "10", "0", "10"]);
+    assert_eq!(balances(&entries[1]), ["10", "0", "10", "0", "10", "10"]);
+    assert_eq!(balances(&entries[2]), ["0", "10", "10", "10", "0", "10"]);
+    assert_eq!(entries[3].currency, Currency::from("EUR"));
+    assert_eq!(balances(&entries[3]), ["10", "0", "10", "6", "0", "6"]);
+    assert_eq!(entries[4].currency, Currency::from("USD"));
+    assert_eq!(entries[4].amount.to_string(), "4.4");
+    assert_eq!(balances(&entries[4]), ["0", "0", "0", "4.4", "0", "4.4"]);
+
+    Ok(())
+}
+
+#[test]
+fn books_balance() -> Result<()> {
+    let mut engine = TransactionEngine::default()
+        .with_rates(RateTable::from_reader(
+            "from,to,rate\nEUR,USD,1.1\n".as_bytes(),
+        )?)
+        .with_fees(FeeSchedule::from_reader(
+            "type,flat,percent\ndeposit,1,\nwithdrawal,,10\n".as_bytes(),
+        )?);
+
+    for (client, transaction_id, kind, amount, to_currency, to_client) in [
+        (0, 0, Kind::Deposit, Some(21), None, None),
+        (
+            0,
+            1,
+            Kind::Exchange,
+            Some(5),
+            Some(Currency::from("USD")),
+            None,
+        ),
+        (0, 2, Kind::Transfer, Some(5), None, Some(1)),
+        (0, 3, Kind::Withdrawal, Some(5), None, None),
+        // Holds more than client 0 has left, so some is written off
+        (0, 0, Kind::Dispute, None, None, None),
+        (0, 0, Kind::Chargeback, None, None, None),
+    ] {
+        engine.add_transaction(Transaction {
+            kind,
+            client,
+            transaction_id,
+            amount: amount.map(Decimal::from),
+            currency: Currency::from("EUR"),
+            to_currency,
+            rate: None,
+            to_client,
+            timestamp: None,
+        })?;
+    }
+
+    let trial_balances = engine.trial_balance();
+    assert_eq!(trial_balances.len(), 2);
+    assert!(trial_balances.iter().all(TrialBalance::is_balanced));
+    crate::verify::verify(&engine)?;
+
+    // 20 was held, but only 4.5 was left after exchanging, transferring and withdrawing
+    // (with a 0.5 fee)
+    let house = engine.house_balances();
+    assert!(house.contains(&(
+        Account::ChargebackLoss,
+        Currency::from("EUR"),
+        Decimal::from_str_exact("15.5")?
+    )));
+    assert_eq!(
+        engine.fees_collected(),
+        vec![(Currency::from("EUR"), Decimal::from_str_exact("0.5")?)]
+    );
+
+    let user_state = engine.user_states.get(&0).unwrap();
+    assert_eq!(
+        user_state.balances[&Currency::from("EUR")].total(),
+        Decimal::ZERO
+    );
+
+    Ok(())
+}
+
+#[test]
+fn shards_merge_into_one_engine() -> Result<()> {
+    let mut engine = TransactionEngine::default().with_fees(FeeSchedule::from_reader(
+        "type,flat,percent\ndeposit,1,\n".as_bytes(),
+    )?);
+
+    let mut shards = [engine.shard(), engine.shard()];
+    for client in 0..4 {
+        shards[usize::from(client % 2)].add_transaction(Transaction {
+            kind: Kind::Deposit,
+            client,
+            transaction_id: u32::from(client),
+            amount: Some(Decimal::from(10)),
+            currency: Currency::default(),
+            to_currency: None,
+            rate: None,
+            to_client: None,
+            timestamp: None,
+        })?;
+    }
+
+    let [first, second] = shards;
+    engine.merge(first)?;
+    engine.merge(second)?;
+
+    assert_eq!(engine.current_account_states().len(), 4);
+    assert_eq!(
+        engine.fees_collected(),
+        vec![(Currency::default(), Decimal::from(4))]
+    );
+    crate::verify::verify(&engine)?;
+
+    // Client 0 is already in the merged engine
+    let mut duplicate = engine.shard();
+    duplicate.user_states.insert(0, UserState::default());
+    assert!(engine.merge(duplicate).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn metrics_count_rejections_holds_and_locks() -> Result<()> {
+    let metrics = Arc::new(Metrics::new()?);
+    let mut engine = TransactionEngine::default().with_metrics(Arc::clone(&metrics));
+
+    let transaction = |kind, transaction_id, amount: Option<i64>| Transaction {
+        kind,
+        client: 0,
+        transaction_id,
+        amount: amount.map(Decimal::from),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    };
+
+    engine.add_transaction(transaction(Kind::Deposit, 0, Some(10)))?;
+    engine.add_transaction(transaction(Kind::Deposit, 1, Some(5)))?;
+    engine.add_transaction(transaction(Kind::Withdrawal, 2, Some(100)))?;
+    engine.add_transaction(transaction(Kind::Dispute, 0, None))?;
+    engine.add_transaction(transaction(Kind::Dispute, 0, None))?;
+    engine.add_transaction(transaction(Kind::Dispute, 1, None))?;
+    engine.add_transaction(transaction(Kind::Resolve, 1, None))?;
+    engine.add_transaction(transaction(Kind::Chargeback, 0, None))?;
+    engine.add_transaction(transaction(Kind::Chargeback, 9, None))?;
+
+    let text = metrics.encode()?;
+    for line in [
+        "payment_engine_transactions_total{kind=\"deposit\"} 2",
+        "payment_engine_transactions_total{kind=\"dispute\"} 3",
+        "payment_engine_rejections_total{reason=\"insufficient_funds\"} 1",
+        "payment_engine_rejections_total{reason=\"already_disputed\"} 1",
+        "payment_engine_rejections_total{reason=\"no_dispute\"} 1",
+        "payment_engine_held{currency=\"USD\"} 0",
+        "payment_engine_locked_accounts 1",
+    ] {
+        assert!(text.lines().any(|actual| actual == line), "{}", text);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn transfers_must_be_positive() -> Result<()> {
+    let mut engine = TransactionEngine::default();
+
+    let transaction = |kind, client, transaction_id, amount: i64, to_client| Transaction {
+        kind,
+        client,
+        transaction_id,
+        amount: Some(Decimal::from(amount)),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client,
+        timestamp: None,
+    };
+
+    engine.add_transaction(transaction(Kind::Deposit, 1, 1, 100, None))?;
+    engine.add_transaction(transaction(Kind::Deposit, 2, 2, 100, None))?;
+    // Would take 100 from client 2 without their say
+    engine.add_transaction(transaction(Kind::Transfer, 1, 3, -100, Some(2)))?;
+    engine.add_transaction(transaction(Kind::Transfer, 1, 4, 0, Some(2)))?;
+
+    for client in [1, 2] {
+        assert_eq!(
+            engine.user_states[&client].balances[&Currency::default()].total(),
+            Decimal::from(100)
+        );
+    }
+    crate::verify::verify(&engine)?;
+
+    Ok(())
+}
+
+#[test]
+fn deposits_and_withdrawals_must_be_positive_and_fit() -> Result<()> {
+    let mut engine = TransactionEngine::default().with_fees(FeeSchedule::from_reader(
+        "type,flat,percent\ndeposit,1,1\nwithdrawal,1,1\n".as_bytes(),
+    )?);
+
+    let transaction = |kind, transaction_id, amount| Transaction {
+        kind,
+        client: 1,
+        transaction_id,
+        amount: Some(amount),
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    };
+
+    engine.add_transaction(transaction(Kind::Deposit, 1, Decimal::from(100)))?;
+    // Would take from the house, with a negative fee
+    engine.add_transaction(transaction(Kind::Deposit, 2, Decimal::from(-10)))?;
+    engine.add_transaction(transaction(Kind::Deposit, 3, Decimal::ZERO))?;
+    engine.add_transaction(transaction(Kind::Withdrawal, 4, Decimal::from(-10)))?;
+    engine.add_transaction(transaction(Kind::Withdrawal, 5, Decimal::ZERO))?;
+    // Their fees overflow
+    engine.add_transaction(transaction(Kind::Deposit, 6, Decimal::MAX))?;
+    engine.add_transaction(transaction(Kind::Withdrawal, 7, Decimal::MAX))?;
+
+    // Less the deposit's fee of 2
+    assert_eq!(
+        engine.user_states[&1].balances[&Currency::default()].total(),
+        Decimal::from(98)
+    );
+    crate::verify::verify(&engine)?;
+
+    Ok(())
+}
+
+#[test]
+fn exchanges_need_a_positive_rate_and_amount() -> Result<()> {
+    let mut engine = TransactionEngine::default().with_rates(RateTable::from_reader(
+        "from,to,rate\nUSD,EUR,0.9\n".as_bytes(),
+    )?);
+
+    let transaction = |kind, transaction_id, amount: i64, rate: Option<i64>| Transaction {
+        kind,
+        client: 1,
+        transaction_id,
+        amount: Some(Decimal::from(amount)),
+        currency: Currency::from("USD"),
+        to_currency: (kind == Kind::Exchange).then(|| Currency::from("EUR")),
+        rate: rate.map(Decimal::from),
+        to_client: None,
+        timestamp: None,
+    };
+
+    engine.add_transaction(transaction(Kind::Deposit, 1, 100, None))?;
+    // Would destroy 100 USD for nothing
+    engine.add_transaction(transaction(Kind::Exchange, 2, 100, Some(0)))?;
+    engine.add_transaction(transaction(Kind::Exchange, 3, 100, Some(-1)))?;
+    // Would leave the client owing EUR
+    engine.add_transaction(transaction(Kind::Exchange, 4, -50, None))?;
+    engine.add_transaction(transaction(Kind::Exchange, 5, 0, None))?;
+
+    let balances = &engine.user_states[&1].balances;
+    assert_eq!(balances[&Currency::from("USD")].total(), Decimal::from(100));
+    assert!(balances
+        .get(&Currency::from("EUR"))
+        .iter()
+        .all(|balance| balance.total().is_zero()));
+    crate::verify::verify(&engine)?;
+
     Ok(())
 }
@@ -0,0 +1,240 @@
+//! Checks that an engine's balances add up
+//!
+//! The engine keeps [`Flows`] of what came in and went out of clients' accounts as it applies
+//! transactions, separately from the accounts themselves. [`check`] compares the two, along
+//! with each client's balances and the trial balance of the books.
+
+use crate::accounts::TrialBalance;
+use crate::prelude::*;
+use crate::transaction::{Currency, UserId};
+use crate::transaction_engine::TransactionEngine;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::AddAssign;
+
+/// What moved in and out of clients' accounts, in a single currency
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Flows {
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+
+    /// The full amount of deposits that were charged back, including their refunded fees
+    pub charged_back: Decimal,
+
+    /// Fees charged, less those refunded on chargeback
+    pub fees: Decimal,
+
+    pub exchanged_in: Decimal,
+    pub exchanged_out: Decimal,
+    pub transferred_in: Decimal,
+    pub transferred_out: Decimal,
+
+    /// What clients owed after chargebacks, which was written off
+    pub written_off: Decimal,
+}
+
+impl AddAssign for Flows {
+    fn add_assign(&mut self, other: Self) {
+        self.deposited += other.deposited;
+        self.withdrawn += other.withdrawn;
+        self.charged_back += other.charged_back;
+        self.fees += other.fees;
+        self.exchanged_in += other.exchanged_in;
+        self.exchanged_out += other.exchanged_out;
+        self.transferred_in += other.transferred_in;
+        self.transferred_out += other.transferred_out;
+        self.written_off += other.written_off;
+    }
+}
+
+impl Flows {
+    /// What clients should have in total after these flows
+    pub fn expected_total(&self) -> Decimal {
+        self.deposited - self.withdrawn - self.charged_back - self.fees + self.exchanged_in
+            - self.exchanged_out
+            + self.transferred_in
+            - self.transferred_out
+            + self.written_off
+    }
+}
+
+/// A way an engine's balances don't add up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// Clients' totals are not what the flows in and out of their accounts add up to
+    Conservation {
+        currency: Currency,
+        expected: Decimal,
+        actual: Decimal,
+    },
+
+    /// A client's available and held funds don't add up to their total
+    ClientBalance {
+        client: UserId,
+        currency: Currency,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    },
+
+    /// The books don't balance
+    TrialBalance(TrialBalance),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conservation {
+                currency,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "clients' totals in {} should be {} but are {} (off by {})",
+                currency,
+                expected,
+                actual,
+                actual - expected
+            ),
+            Self::ClientBalance {
+                client,
+                currency,
+                available,
+                held,
+                total,
+            } => write!(
+                f,
+                "client {} has {} available and {} held in {}, which is {} but their total is {}",
+                client,
+                available,
+                held,
+                currency,
+                available + held,
+                total
+            ),
+            Self::TrialBalance(trial_balance) => write!(
+                f,
+                "the books don't balance in {}, with {} in debits and {} in credits",
+                trial_balance.currency, trial_balance.debits, trial_balance.credits
+            ),
+        }
+    }
+}
+
+/// Every way the engine's balances don't add up
+pub fn check(engine: &TransactionEngine) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let mut totals: HashMap<Currency, Decimal> = HashMap::new();
+
+    for summary in engine.current_account_states() {
+        if summary.available + summary.held != summary.total {
+            violations.push(Violation::ClientBalance {
+                client: summary.client,
+                currency: summary.currency.clone(),
+                available: summary.available,
+                held: summary.held,
+                total: summary.total,
+            });
+        }
+
+        *totals.entry(summary.currency).or_default() += summary.total;
+    }
+
+    for (currency, flows) in engine.flows() {
+        let expected = flows.expected_total();
+        let actual = totals.remove(&currency).unwrap_or_default();
+
+        if expected != actual {
+            violations.push(Violation::Conservation {
+                currency,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    // Anything left never flowed in, so should be zero
+    for (currency, actual) in totals {
+        if !actual.is_zero() {
+            violations.push(Violation::Conservation {
+                currency,
+                expected: Decimal::ZERO,
+                actual,
+            });
+        }
+    }
+
+    violations.extend(
+        engine
+            .trial_balance()
+            .into_iter()
+            .filter(|trial_balance| !trial_balance.is_balanced())
+            .map(Violation::TrialBalance),
+    );
+
+    violations
+}
+
+/// Fails, listing every violation, if the engine's balances don't add up
+pub fn verify(engine: &TransactionEngine) -> Result<()> {
+    let violations = check(engine);
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!("{} invariants broken:", violations.len());
+    for violation in violations {
+        message.push_str(&format!("\n  - {}", violation));
+    }
+
+    Err(anyhow!(message))
+}
+
+#[test]
+fn overheld_client_still_adds_up() -> Result<()> {
+    use crate::transaction::{Kind, Transaction};
+
+    let mut engine = TransactionEngine::default();
+    for (transaction_id, kind, amount) in [
+        (0, Kind::Deposit, Some(10)),
+        (1, Kind::Withdrawal, Some(4)),
+        (2, Kind::Deposit, Some(3)),
+    ] {
+        engine.add_transaction(Transaction {
+            kind,
+            client: 0,
+            transaction_id,
+            amount: amount.map(Decimal::from),
+            currency: Currency::default(),
+            to_currency: None,
+            rate: None,
+            to_client: None,
+            timestamp: None,
+        })?;
+    }
+    verify(&engine)?;
+
+    // Holds 10, when only 9 is left, so 1 less than nothing is available
+    engine.add_transaction(Transaction {
+        kind: Kind::Dispute,
+        client: 0,
+        transaction_id: 0,
+        amount: None,
+        currency: Currency::default(),
+        to_currency: None,
+        rate: None,
+        to_client: None,
+        timestamp: None,
+    })?;
+
+    verify(&engine)?;
+    let summary = &engine.current_account_states()[0];
+    assert_eq!(
+        (summary.available, summary.held, summary.total),
+        (Decimal::from(-1), Decimal::from(10), Decimal::from(9))
+    );
+
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Random sequences of transactions, checked against a simple reference model of the engine
+
+use payment_engine::transaction::{Currency, Kind, Transaction, TransactionId, UserId};
+use payment_engine::transaction_engine::{Lock, TransactionEngine, UserSummary};
+use payment_engine::verify::{self, Violation};
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DepositState {
+    Undisputed,
+    Disputed,
+    ChargedBack,
+}
+
+#[derive(Default, Debug, Clone)]
+struct ModelClient {
+    /// Can go below zero, when a dispute holds more than was available
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+
+    /// The first deposit with each transaction ID
+    deposits: HashMap<TransactionId, (Decimal, DepositState)>,
+}
+
+/// The engine's rules for deposits, withdrawals and disputes, without fees, limits, currencies
+/// or concurrency
+#[derive(Default, Debug)]
+struct Model {
+    clients: HashMap<UserId, ModelClient>,
+}
+
+impl Model {
+    /// Applies a transaction, returning whether it was accepted
+    fn apply(&mut self, transaction: &Transaction) -> bool {
+        let client = self.clients.entry(transaction.client).or_default();
+        let deposit = client.deposits.get_mut(&transaction.transaction_id);
+
+        match (transaction.kind, deposit) {
+            (Kind::Deposit, _) => {
+                let amount = transaction.amount.unwrap();
+                client.available += amount;
+                client
+                    .deposits
+                    .entry(transaction.transaction_id)
+                    .or_insert((amount, DepositState::Undisputed));
+                true
+            }
+            (Kind::Withdrawal, _) => {
+                let amount = transaction.amount.unwrap();
+                if client.available < amount {
+                    return false;
+                }
+                client.available -= amount;
+                true
+            }
+            (Kind::Dispute, Some((amount, state))) if *state == DepositState::Undisputed => {
+                *state = DepositState::Disputed;
+                client.available -= *amount;
+                client.held += *amount;
+                true
+            }
+            (Kind::Resolve, Some((amount, state))) if *state == DepositState::Disputed => {
+                *state = DepositState::Undisputed;
+                client.available += *amount;
+                client.held -= *amount;
+                true
+            }
+            (Kind::Chargeback, Some((amount, state))) if *state == DepositState::Disputed => {
+                *state = DepositState::ChargedBack;
+                let shortfall = (*amount - client.available - client.held).max(Decimal::ZERO);
+                client.held -= *amount;
+                client.available += shortfall;
+                client.locked = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn summary(&self, client: UserId) -> (Decimal, Decimal, Decimal, Lock) {
+        let state = &self.clients[&client];
+        (
+            state.available,
+            state.held,
+            state.available + state.held,
+            if state.locked {
+                Lock::Locked
+            } else {
+                Lock::Unlocked
+            },
+        )
+    }
+}
+
+fn summaries(engine: &TransactionEngine) -> HashMap<UserId, UserSummary> {
+    engine
+        .current_account_states()
+        .into_iter()
+        .map(|summary| (summary.client, summary))
+        .collect()
+}
+
+fn transaction() -> impl Strategy<Value = Transaction> {
+    let kind = prop_oneof![
+        3 => Just(Kind::Deposit),
+        2 => Just(Kind::Withdrawal),
+        2 => Just(Kind::Dispute),
+        1 => Just(Kind::Resolve),
+        1 => Just(Kind::Chargeback),
+    ];
+
+    (kind, 0..4 as UserId, 0..16 as TransactionId, 1..10_000i64).prop_map(
+        |(kind, client, transaction_id, cents)| Transaction {
+            kind,
+            client,
+            transaction_id,
+            amount: match kind {
+                Kind::Deposit | Kind::Withdrawal => Some(Decimal::new(cents, 2)),
+                _ => None,
+            },
+            currency: Currency::default(),
+            to_currency: None,
+            rate: None,
+            to_client: None,
+            timestamp: None,
+        },
+    )
+}
+
+proptest! {
+    #[test]
+    fn engine_matches_model(transactions in prop::collection::vec(transaction(), 1..200)) {
+        let mut engine = TransactionEngine::default();
+        let mut model = Model::default();
+
+        for transaction in transactions {
+            let before = summaries(&engine);
+            let accepted = model.apply(&transaction);
+            engine.add_transaction(transaction.clone()).unwrap();
+            let after = summaries(&engine);
+
+            // Every client adds up after every transaction, including disputes of deposits
+            // that were already withdrawn
+            for summary in after.values() {
+                prop_assert_eq!(
+                    summary.total,
+                    summary.available + summary.held,
+                    "after {:?}",
+                    transaction
+                );
+            }
+
+            let summary = &after[&transaction.client];
+            prop_assert_eq!(
+                (summary.available, summary.held, summary.total, summary.locked),
+                model.summary(transaction.client),
+                "after {:?}",
+                transaction
+            );
+
+            // Rejected transactions change nothing, besides the client now being known
+            if !accepted {
+                if let Some(previous) = before.get(&transaction.client) {
+                    prop_assert_eq!(
+                        (previous.available, previous.held, previous.total, previous.locked),
+                        (summary.available, summary.held, summary.total, summary.locked)
+                    );
+                }
+            }
+        }
+
+        let model_chargebacks: HashMap<UserId, bool> = model
+            .clients
+            .iter()
+            .map(|(client, state)| (*client, state.locked))
+            .collect();
+
+        for summary in summaries(&engine).values() {
+            prop_assert!(summary.held >= Decimal::ZERO);
+            prop_assert_eq!(summary.total, summary.available + summary.held);
+
+            // Clients are locked by chargebacks, and nothing else
+            prop_assert_eq!(
+                summary.locked == Lock::Locked,
+                model_chargebacks[&summary.client]
+            );
+        }
+
+        let violations: Vec<Violation> = verify::check(&engine);
+        prop_assert!(violations.is_empty(), "{:?}", violations);
+    }
+}
+
+#[test]
+fn disputing_withdrawn_funds_still_adds_up() {
+    let mut engine = TransactionEngine::default();
+
+    for (transaction_id, kind, amount) in [
+        (0, Kind::Deposit, Some(10)),
+        (1, Kind::Withdrawal, Some(4)),
+        (0, Kind::Dispute, None),
+    ] {
+        engine
+            .add_transaction(Transaction {
+                kind,
+                client: 0,
+                transaction_id,
+                amount: amount.map(Decimal::from),
+                currency: Currency::default(),
+                to_currency: None,
+                rate: None,
+                to_client: None,
+                timestamp: None,
+            })
+            .unwrap();
+    }
+
+    let summary = &summaries(&engine)[&0];
+    assert_eq!(summary.total, summary.available + summary.held);
+    assert_eq!(verify::check(&engine), Vec::new());
+}
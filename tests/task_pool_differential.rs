@@ -0,0 +1,146 @@
+//! The same input through a single engine and through a `TaskPool`, which must agree
+//!
+//! The pool shards clients across workers and relies on each client's transactions staying in
+//! order, and on transfers between workers being applied in order, so any ordering regression
+//! shows up as a difference in the output.
+
+use payment_engine::prelude::*;
+use payment_engine::rates::RateTable;
+use payment_engine::sharding::Sharding;
+use payment_engine::task_pool::TaskPool;
+use payment_engine::transaction::{Currency, Kind, Transaction, TransactionId, UserId};
+use payment_engine::transaction_engine::TransactionEngine;
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use std::time::Duration;
+
+const WORKERS: [isize; 4] = [1, 2, 3, 4];
+const QUEUE_DEPTHS: [usize; 3] = [1, 7, 1_000];
+const BATCH_SIZES: [usize; 3] = [1, 5, 64];
+const SHARDINGS: [Sharding; 3] = [Sharding::Modulo, Sharding::Hash, Sharding::Rebalance];
+
+/// Small enough that clients are moved many times over a few hundred transactions
+const REBALANCE_EVERY: u64 = 16;
+
+fn engine() -> Result<TransactionEngine> {
+    Ok(
+        TransactionEngine::default().with_rates(RateTable::from_reader(
+            "from,to,rate\nUSD,EUR,0.9\n".as_bytes(),
+        )?),
+    )
+}
+
+/// The output CSV, sorted so it doesn't depend on the order clients were stored in
+fn output(engine: &TransactionEngine) -> Result<Vec<u8>> {
+    let mut summaries = engine.current_account_states();
+    summaries.sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for summary in summaries {
+        writer.serialize(summary)?;
+    }
+
+    Ok(writer.into_inner()?)
+}
+
+fn sequential(transactions: &[Transaction]) -> Result<Vec<u8>> {
+    let mut engine = engine()?;
+    for transaction in transactions {
+        engine.add_transaction(transaction.clone())?;
+    }
+
+    output(&engine)
+}
+
+fn pooled(
+    transactions: &[Transaction],
+    workers: isize,
+    queue_depth: usize,
+    batch_size: usize,
+    sharding: Sharding,
+) -> Result<Vec<u8>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let mut task_pool = TaskPool::new(engine()?, queue_depth, workers)
+            .with_batching(batch_size, Duration::from_secs(60))
+            .with_sharding(sharding)
+            .with_rebalance_every(REBALANCE_EVERY);
+        for transaction in transactions {
+            task_pool.add_transaction(transaction.clone()).await?;
+        }
+
+        output(&task_pool.wait().await?)
+    })
+}
+
+fn transaction() -> impl Strategy<Value = Transaction> {
+    let kind = prop_oneof![
+        4 => Just(Kind::Deposit),
+        2 => Just(Kind::Withdrawal),
+        2 => Just(Kind::Transfer),
+        1 => Just(Kind::Exchange),
+        2 => Just(Kind::Dispute),
+        1 => Just(Kind::Resolve),
+        1 => Just(Kind::Chargeback),
+    ];
+
+    (
+        kind,
+        0..8 as UserId,
+        0..8 as UserId,
+        0..32 as TransactionId,
+        1..10_000i64,
+    )
+        .prop_map(|(kind, client, to_client, transaction_id, cents)| {
+            let amount = match kind {
+                Kind::Dispute | Kind::Resolve | Kind::Chargeback => None,
+                _ => Some(Decimal::new(cents, 2)),
+            };
+
+            Transaction {
+                kind,
+                client,
+                transaction_id,
+                amount,
+                currency: Currency::default(),
+                to_currency: (kind == Kind::Exchange).then(|| Currency::from("EUR")),
+                rate: None,
+                to_client: (kind == Kind::Transfer).then_some(to_client),
+                timestamp: None,
+            }
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn task_pool_matches_sequential(transactions in prop::collection::vec(transaction(), 1..300)) {
+        let expected = sequential(&transactions).unwrap();
+
+        for workers in WORKERS {
+            for queue_depth in QUEUE_DEPTHS {
+                for batch_size in BATCH_SIZES {
+                    for sharding in SHARDINGS {
+                        let actual =
+                            pooled(&transactions, workers, queue_depth, batch_size, sharding)
+                                .unwrap();
+                        prop_assert_eq!(
+                            String::from_utf8_lossy(&actual),
+                            String::from_utf8_lossy(&expected),
+                            "with {} workers, a queue depth of {}, batches of {} and {:?} sharding",
+                            workers,
+                            queue_depth,
+                            batch_size,
+                            sharding
+                        );
+                    }
+                }
+            }
+        }
+    }
+}